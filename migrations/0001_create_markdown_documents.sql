@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS markdown_documents (
+    id TEXT PRIMARY KEY,
+    content TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    expires_at DATETIME NOT NULL
+);
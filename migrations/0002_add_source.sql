@@ -0,0 +1 @@
+ALTER TABLE markdown_documents ADD COLUMN source TEXT;
@@ -0,0 +1 @@
+ALTER TABLE markdown_documents ADD COLUMN delete_token TEXT;
@@ -0,0 +1,17 @@
+-- SQLite cannot relax a column constraint in place, so the table is rebuilt
+-- with the same rows and a nullable expires_at for documents that never expire.
+CREATE TABLE markdown_documents_new (
+    id TEXT PRIMARY KEY,
+    content TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    expires_at DATETIME,
+    source TEXT,
+    delete_token TEXT
+);
+
+INSERT INTO markdown_documents_new (id, content, created_at, expires_at, source, delete_token)
+SELECT id, content, created_at, expires_at, source, delete_token FROM markdown_documents;
+
+DROP TABLE markdown_documents;
+
+ALTER TABLE markdown_documents_new RENAME TO markdown_documents;
@@ -0,0 +1,2 @@
+ALTER TABLE markdown_documents ADD COLUMN edit_token TEXT;
+ALTER TABLE markdown_documents ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
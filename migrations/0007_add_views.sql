@@ -0,0 +1 @@
+ALTER TABLE markdown_documents ADD COLUMN views INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE markdown_documents ADD COLUMN password_hash TEXT;
+ALTER TABLE markdown_documents ADD COLUMN unlock_token TEXT;
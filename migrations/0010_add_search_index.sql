@@ -0,0 +1,10 @@
+-- Full-text index used by the opt-in /search endpoint. Rows are only added
+-- when SEARCH_ENABLED is set, but removals follow the documents table so
+-- deleted and purged documents never linger in the index.
+CREATE VIRTUAL TABLE IF NOT EXISTS markdown_documents_fts USING fts5(id UNINDEXED, title, content);
+
+CREATE TRIGGER IF NOT EXISTS markdown_documents_fts_delete
+AFTER DELETE ON markdown_documents
+BEGIN
+    DELETE FROM markdown_documents_fts WHERE id = old.id;
+END;
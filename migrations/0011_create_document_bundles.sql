@@ -0,0 +1,8 @@
+-- Several markdown documents shared together under one id and viewed as
+-- tabs. The documents are stored as a JSON array of {name, content}.
+CREATE TABLE IF NOT EXISTS document_bundles (
+    id TEXT PRIMARY KEY,
+    documents TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    expires_at DATETIME
+);
@@ -0,0 +1,3 @@
+-- Whether the author allowed search engines to index the document. Existing
+-- documents were shared without being asked, so they stay out of the index.
+ALTER TABLE markdown_documents ADD COLUMN indexable BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- HTML rendered from the content when it was shared or last edited, so views
+-- serve it directly. Documents shared before this column existed are
+-- rendered on view instead.
+ALTER TABLE markdown_documents ADD COLUMN rendered_html TEXT;
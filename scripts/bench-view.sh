@@ -0,0 +1,62 @@
+#!/bin/sh
+# Measures serving a large shared document. Shares a generated markdown file
+# of SIZE_MB megabytes, fetches its view page RUNS times, then reports the
+# response times and the server's peak resident memory (Linux only).
+#
+#   cargo build --release && scripts/bench-view.sh [SIZE_MB] [RUNS]
+set -eu
+
+SIZE_MB=${1:-1}
+RUNS=${2:-10}
+BIN=${BIN:-target/release/mdow}
+PORT=${PORT:-18081}
+WORK=$(mktemp -d)
+SERVER=
+
+cleanup() {
+    [ -n "$SERVER" ] && kill "$SERVER" 2>/dev/null
+    rm -rf "$WORK"
+}
+trap cleanup EXIT
+
+awk -v bytes=$((SIZE_MB * 1024 * 1024)) 'BEGIN {
+    for (i = 0; written < bytes; i++) {
+        section = sprintf("## Section %d\n\nSome *text* with a [link](https://example.com/%d) and `code`.\n\n- [x] done\n- [ ] open\n\n```rust\nfn main() { println!(\"%d\"); }\n```\n\n", i, i, i)
+        printf "%s", section
+        written += length(section)
+    }
+}' > "$WORK/doc.md"
+
+DATABASE_URL="sqlite:$WORK/bench.db" \
+PORT=$PORT \
+BIND_ADDR=127.0.0.1 \
+MAX_CONTENT_BYTES=$((SIZE_MB * 2 * 1024 * 1024)) \
+SHARE_RATE_LIMIT=0 \
+PURGE_EXPIRED=false \
+RUST_LOG=warn \
+    "$BIN" &
+SERVER=$!
+
+until curl -sf "http://127.0.0.1:$PORT/health" > /dev/null; do
+    sleep 0.2
+done
+
+URL=$(curl -sf -H 'Accept: text/plain' --data-urlencode "content@$WORK/doc.md" \
+    "http://127.0.0.1:$PORT/share")
+VIEW_PATH=/view/${URL##*/view/}
+PAGE_BYTES=$(curl -s "http://127.0.0.1:$PORT$VIEW_PATH" | wc -c)
+
+i=0
+while [ "$i" -lt "$RUNS" ]; do
+    curl -s -o /dev/null -w '%{time_total}\n' "http://127.0.0.1:$PORT$VIEW_PATH"
+    i=$((i + 1))
+done | awk -v size="$SIZE_MB" -v page="$PAGE_BYTES" '
+    { total += $1; if (NR == 1 || $1 < min) min = $1; if ($1 > max) max = $1 }
+    END {
+        printf "document: %d MB, page: %.1f MB, views: %d\n", size, page / 1048576, NR
+        printf "response time: min %.0f ms, avg %.0f ms, max %.0f ms\n", min * 1000, total / NR * 1000, max * 1000
+    }'
+
+if [ -r "/proc/$SERVER/status" ]; then
+    grep VmHWM "/proc/$SERVER/status" | awk '{ printf "peak RSS: %.1f MB\n", $2 / 1024 }'
+fi
@@ -0,0 +1,145 @@
+//! Parsing of the YAML front matter block that may open a document.
+
+const MAX_KEYWORDS: usize = 20;
+
+/// The fields of a leading YAML front matter block that mdow shows.
+#[derive(Default)]
+pub struct FrontMatter {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub date: Option<String>,
+    /// Unsanitized entries of the `keywords:` (or `tags:`) list.
+    pub keywords: Vec<String>,
+}
+
+/// Splits a leading `---` front matter block from the body. Only flat
+/// `key: value` lines and the keyword list are understood; other nested
+/// values and lists are skipped. The keyword list may be written inline
+/// (`keywords: [a, b]` / `keywords: a, b`) or as `- item` lines. Anything
+/// that does not look like front matter, such as a thematic break followed
+/// by prose, is left in the body untouched.
+pub fn parse_front_matter(markdown_content: &str) -> (Option<FrontMatter>, &str) {
+    let Some(rest) = markdown_content
+        .strip_prefix("---\n")
+        .or_else(|| markdown_content.strip_prefix("---\r\n"))
+    else {
+        return (None, markdown_content);
+    };
+
+    let mut front_matter = FrontMatter::default();
+    let mut in_keyword_list = false;
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        offset += line.len();
+        let line = line.trim_end();
+        if line == "---" || line == "..." {
+            return (Some(front_matter), &rest[offset..]);
+        }
+        if in_keyword_list {
+            if let Some(item) = line.trim_start().strip_prefix("- ") {
+                front_matter.keywords.push(item.to_string());
+                continue;
+            }
+            in_keyword_list = false;
+        }
+        if line.trim().is_empty()
+            || line.trim_start().starts_with('#')
+            || line.starts_with([' ', '\t', '-'])
+        {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once(':') else {
+            return (None, markdown_content);
+        };
+        if key.trim().is_empty() {
+            return (None, markdown_content);
+        }
+        if matches!(key.trim(), "keywords" | "tags") && front_matter.keywords.is_empty() {
+            let list = value.trim().trim_start_matches('[').trim_end_matches(']');
+            if list.is_empty() {
+                in_keyword_list = true;
+            } else {
+                front_matter
+                    .keywords
+                    .extend(list.split(',').map(str::to_string));
+            }
+            continue;
+        }
+        let value = unquote_yaml_scalar(value.trim());
+        let value = (!value.is_empty()).then(|| value.to_string());
+        match key.trim() {
+            "title" => front_matter.title = value,
+            "author" => front_matter.author = value,
+            "date" => front_matter.date = value,
+            _ => {}
+        }
+    }
+
+    (None, markdown_content)
+}
+
+fn unquote_yaml_scalar(value: &str) -> &str {
+    ['"', '\'']
+        .iter()
+        .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+        .unwrap_or(value)
+        .trim()
+}
+
+/// Reads the sanitized `keywords:` (or `tags:`) list from the front matter.
+pub fn extract_front_matter_keywords(markdown_content: &str) -> Option<Vec<String>> {
+    let (front_matter, _) = parse_front_matter(markdown_content);
+    let keywords: Vec<String> = front_matter?
+        .keywords
+        .iter()
+        .map(|keyword| sanitize_keyword(keyword))
+        .filter(|keyword| !keyword.is_empty())
+        .take(MAX_KEYWORDS)
+        .collect();
+    (!keywords.is_empty()).then_some(keywords)
+}
+
+fn sanitize_keyword(keyword: &str) -> String {
+    keyword
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'')
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.'))
+        .take(50)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn front_matter_fields_and_keywords_come_from_one_parser() {
+        let content = "---\ntitle: \"Notes\"\nauthor: Ada\ntags:\n  - rust\n  - web\n---\n# Body\n";
+        let (front_matter, body) = parse_front_matter(content);
+        let front_matter = front_matter.unwrap();
+        assert_eq!(front_matter.title.as_deref(), Some("Notes"));
+        assert_eq!(front_matter.author.as_deref(), Some("Ada"));
+        assert_eq!(body, "# Body\n");
+        assert_eq!(
+            extract_front_matter_keywords(content),
+            Some(vec!["rust".to_string(), "web".to_string()])
+        );
+        assert_eq!(
+            extract_front_matter_keywords("---\nkeywords: [a, \"b\"]\n---\ntext"),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+    }
+
+    #[test]
+    fn malformed_front_matter_stays_in_the_body() {
+        let content = "---\nJust a thematic break above prose\n---\nkeywords: a\n";
+        let (front_matter, body) = parse_front_matter(content);
+        assert!(front_matter.is_none());
+        assert_eq!(body, content);
+        assert_eq!(extract_front_matter_keywords(content), None);
+    }
+}
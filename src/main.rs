@@ -1,31 +1,296 @@
-use ammonia::clean;
+mod front_matter;
+
+use argon2::{
+    password_hash::{
+        self, rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
+    Argon2,
+};
 use axum::{
-    extract::{Form, Path, Query, State},
-    http::StatusCode,
-    response::{Html, IntoResponse},
+    body::{Bytes, StreamBody},
+    extract::{
+        rejection::{FormRejection, JsonRejection, QueryRejection},
+        ConnectInfo, DefaultBodyLimit, Form, FromRef, Path, Query, State,
+    },
+    http::{
+        header::{
+            ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH,
+            CONTENT_SECURITY_POLICY, CONTENT_TYPE, COOKIE, ETAG, IF_NONE_MATCH, REFERRER_POLICY,
+            RETRY_AFTER, SET_COOKIE, USER_AGENT, VARY, WWW_AUTHENTICATE, X_CONTENT_TYPE_OPTIONS,
+            X_FRAME_OPTIONS,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
-    Router,
+    Json, Router,
 };
 use chrono::{DateTime, Utc};
-use maud::{html, Markup, PreEscaped};
-use pulldown_cmark::{html::push_html, Options, Parser};
-use qrcode::{render::svg, QrCode};
-use serde::Deserialize;
+use front_matter::{extract_front_matter_keywords, parse_front_matter};
+use futures_util::stream;
+use maud::{html, Markup, PreEscaped, DOCTYPE};
+use moka::sync::Cache;
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, Registry, TextEncoder};
+use pulldown_cmark::{
+    escape::{escape_href, escape_html},
+    html::push_html,
+    CodeBlockKind, CowStr, Event, HeadingLevel, Options, Parser, Tag,
+};
+use qrcode::{render::svg, types::QrError, EcLevel, QrCode};
+use serde::{Deserialize, Serialize};
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
-use std::net::SocketAddr;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::Infallible;
+use std::future::Future;
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::path::{Component, Path as FilePath, PathBuf};
+use std::pin::Pin;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use syntect::highlighting::ThemeSet;
+use syntect::html::highlighted_html_for_string;
+use syntect::parsing::SyntaxSet;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tower_http::compression::{
+    predicate::{DefaultPredicate, Predicate, SizeAbove},
+    CompressionLayer,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::EnvFilter;
+use url::Host;
 use uuid::Uuid;
 
 const DEFAULT_PORT: u16 = 8081;
 const DEFAULT_DB_PATH: &str = "sqlite:data/database.db";
 const DOCUMENT_EXPIRY_DAYS: i64 = 30;
+const SHORT_ID_LENGTH: usize = 7;
+const MIN_ID_LENGTH: usize = 4;
+const MAX_ID_LENGTH: usize = 32;
+const ID_ATTEMPTS_PER_LENGTH: usize = 3;
+const MAX_ID_WIDENING: usize = 4;
+const MIN_EXPIRY_DAYS: i64 = 1;
+const MAX_REQUESTED_EXPIRY_DAYS: i64 = 365;
+const DEFAULT_HIGHLIGHT_THEME: &str = "InspiredGitHub";
+const STANDALONE_STYLESHEET: &str = include_str!("../assets/standalone.css");
+const CALLOUT_TYPES: [(&str, &str); 5] = [
+    ("note", "Note"),
+    ("tip", "Tip"),
+    ("important", "Important"),
+    ("warning", "Warning"),
+    ("caution", "Caution"),
+];
+/// Elements the renderer itself emits, and the only ones a document keeps
+/// by default.
+const RENDERED_HTML_TAGS: [&str; 37] = [
+    "a",
+    "abbr",
+    "blockquote",
+    "br",
+    "button",
+    "code",
+    "dd",
+    "del",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "img",
+    "input",
+    "li",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "span",
+    "strong",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "tr",
+    "ul",
+];
+/// Harmless elements a deployment may let authors write as inline HTML
+/// through `ALLOWED_HTML_TAGS`.
+const OPTIONAL_HTML_TAGS: [&str; 15] = [
+    "details",
+    "summary",
+    "kbd",
+    "mark",
+    "sub",
+    "ins",
+    "s",
+    "small",
+    "u",
+    "samp",
+    "var",
+    "cite",
+    "q",
+    "figure",
+    "figcaption",
+];
+const VIEWER_STYLESHEET: &str = include_str!("../assets/viewer.css");
+/// Handles the copy buttons of every code block on the page. Rendered
+/// documents go through the sanitizer, which drops `_` attributes, so the
+/// handler lives on the body rather than on each button.
+const COPY_CODE_HYPERSCRIPT: &str = "on click
+  set button to event.target.closest('.copy-code')
+  if button is not null
+    call navigator.clipboard.writeText(button.parentElement.querySelector('pre').textContent)
+    put 'Copied!' into button
+    wait 2s
+    put 'Copy' into button
+  end";
+/// Shows error responses of editor requests. htmx doesn't swap 4xx and 5xx
+/// responses, so for requests from inside an element with a
+/// `data-error-target` the body is swapped into that target instead, and
+/// cleared again when the next request starts.
+const EDITOR_ERROR_HYPERSCRIPT: &str = "on htmx:beforeRequest
+  set requester to event.detail.elt.closest('[data-error-target]')
+  if requester is not null
+    set errorBox to document.querySelector(requester.dataset.errorTarget)
+    set errorBox.innerHTML to ''
+  end
+end
+on htmx:beforeSwap
+  set requester to event.detail.requestConfig.elt.closest('[data-error-target]')
+  if event.detail.isError and requester is not null
+    set event.detail.shouldSwap to true
+    set event.detail.target to document.querySelector(requester.dataset.errorTarget)
+  end
+end";
+const EXPIRY_CHOICES: &[(i64, &str)] = &[
+    (1, "1 day"),
+    (7, "1 week"),
+    (30, "30 days"),
+    (90, "90 days"),
+    (365, "1 year"),
+];
+const DEFAULT_DB_SHARDS: usize = 1;
+const MAX_DB_SHARDS: usize = 16;
+const DEFAULT_KEYWORDS: &str = "markdown editor, online markdown, markdown preview, markdown sharing, web markdown, browser markdown";
+const DEFAULT_MAX_NESTING_DEPTH: usize = 64;
+const DEFAULT_MAX_CONTENT_BYTES: usize = 1024 * 1024;
+const BODY_LIMIT_SLACK_BYTES: usize = 64 * 1024;
+const DEFAULT_PURGE_INTERVAL_SECS: u64 = 60 * 60;
+const MAX_SOURCE_CHARS: usize = 200;
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_URL_SLUG_CHARS: usize = 60;
+const RECENT_DOCUMENTS_LIMIT: usize = 20;
+const MAX_BUNDLE_DOCUMENTS: usize = 10;
+const MAX_BUNDLE_NAME_CHARS: usize = 100;
+const DOCUMENT_BODY_PLACEHOLDER: &str = "<!--mdow:document-->";
+const COMPRESSION_MIN_BYTES: u16 = 1024;
+const VIEW_CACHE_MAX_AGE_SECS: u64 = 5 * 60;
+const SEARCH_RESULT_LIMIT: usize = 20;
+const IMPORT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_LOG_FILTER: &str = "mdow=info,tower_http=info";
+const QR_MIN_SIZE: u32 = 64;
+const QR_MAX_SIZE: u32 = 256;
+const READING_WORDS_PER_MINUTE: usize = 200;
+const OG_EXCERPT_CHARS: usize = 160;
+const DOCUMENT_PASSWORD_HEADER: &str = "x-document-password";
+const DEFAULT_STYLESHEET_URL: &str = "https://yree.io/mold/assets/css/main.css";
+const DEFAULT_MATHJAX_URL: &str = "https://cdn.jsdelivr.net/npm/mathjax@3/es5/tex-mml-chtml.js";
+const DEFAULT_HTMX_URL: &str = "https://unpkg.com/htmx.org@1.9.10";
+const DEFAULT_HYPERSCRIPT_URL: &str = "https://unpkg.com/hyperscript.org@0.9.12";
+const DEFAULT_MERMAID_URL: &str = "https://cdn.jsdelivr.net/npm/mermaid@10/dist/mermaid.min.js";
+const DEFAULT_GOATCOUNTER_URL: &str = "https://yree.goatcounter.com/count";
+const DEFAULT_GOATCOUNTER_SCRIPT_URL: &str = "//gc.zgo.at/count.js";
+const MERMAID_BLOCK_OPEN_TAG: &str = "<pre class=\"mermaid\">";
+const DEFAULT_BASE_URL: &str = "https://mdow.yree.io";
+const EXTERNAL_LINK_INDICATOR: &str = " ↗";
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 300;
+const DEFAULT_RENDER_QUEUE_TIMEOUT_MS: u64 = 2000;
+const RENDER_RETRY_AFTER_SECS: u64 = 1;
+const DEFAULT_RENDER_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_SHARE_RATE_LIMIT: u32 = 10;
+const DEFAULT_SHARE_RATE_WINDOW_SECS: u64 = 60;
+const MAX_TRACKED_SHARE_CLIENTS: usize = 10_000;
+const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+const DEFAULT_X_FRAME_OPTIONS: &str = "DENY";
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+static SYNTAX_SET: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEME_SET: LazyLock<ThemeSet> = LazyLock::new(ThemeSet::load_defaults);
+static ASSET_URLS: LazyLock<AssetUrls> = LazyLock::new(AssetUrls::from_env);
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Sanitizer for rendered documents. It keeps only the elements our renderer
+/// emits, plus any opted into with `ALLOWED_HTML_TAGS`, along with the
+/// markup they need: heading ids, classes on code blocks and diagrams, code
+/// copy buttons, callouts, footnote and table-of-contents structure, task
+/// lists and their checkboxes, the inline colors of highlighted code and
+/// `target="_blank"` on external links. Every link also gets ammonia's
+/// `rel="noopener noreferrer"`.
+/// Scripts, event handlers and other attributes are still removed.
+static SANITIZER: LazyLock<ammonia::Builder<'static>> =
+    LazyLock::new(|| create_sanitizer(get_allowed_html_tags()));
+
+fn create_sanitizer(allowed_html_tags: Vec<&'static str>) -> ammonia::Builder<'static> {
+    let mut builder = ammonia::Builder::default();
+    builder
+        .tags(RENDERED_HTML_TAGS.into_iter().collect())
+        .add_tags(allowed_html_tags)
+        .add_tag_attributes("details", ["open"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .add_tag_attributes("button", ["type", "class"])
+        .add_tag_attributes("p", ["class"])
+        .add_tag_attributes("li", ["id", "value", "class"])
+        .add_tag_attributes("ul", ["class"])
+        .add_tag_attributes("ol", ["class"])
+        .add_tag_attributes("sup", ["id", "class"])
+        .add_tag_attributes("a", ["class", "target"])
+        .add_tag_attributes("nav", ["class"])
+        .add_tag_attributes("section", ["class"])
+        .add_tag_attributes("div", ["class"])
+        .add_tag_attributes("pre", ["class", "style"])
+        .add_tag_attributes("code", ["class"])
+        .add_tag_attributes("span", ["class", "style"])
+        .add_tag_attributes("th", ["style"])
+        .add_tag_attributes("td", ["style"])
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            (_, "style") => is_allowed_inline_style(value).then_some(value.into()),
+            ("input", "type") => (value == "checkbox").then_some(value.into()),
+            ("a", "target") => (value == "_blank").then_some(value.into()),
+            _ => Some(value.into()),
+        });
+    for heading in ["h1", "h2", "h3", "h4", "h5", "h6"] {
+        builder.add_tag_attributes(heading, ["id", "class"]);
+    }
+    builder
+}
+
 #[derive(Deserialize)]
 struct MarkdownInput {
     content: String,
+    source: Option<String>,
+    terms_accepted: Option<String>,
+    expiry_days: Option<i64>,
+    never_expires: Option<String>,
+    theme: Option<String>,
+    password: Option<String>,
+    title: Option<String>,
+    indexable: Option<String>,
 }
 
 #[derive(sqlx::FromRow)]
@@ -33,411 +298,7045 @@ struct MarkdownDocument {
     id: String,
     content: String,
     created_at: DateTime<Utc>,
-    expires_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    source: Option<String>,
+    delete_token: Option<String>,
+    edit_token: Option<String>,
+    version: i64,
+    theme: String,
+    views: i64,
+    password_hash: Option<String>,
+    unlock_token: Option<String>,
+    title: Option<String>,
+    indexable: bool,
+    rendered_html: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct RenderParams {
-    content: Option<String>,
+/// One named document of a bundle, as stored in its JSON array.
+#[derive(Serialize, Deserialize)]
+struct BundleEntry {
+    name: String,
+    content: String,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let pool = setup_database().await?;
-    let app = setup_router(pool);
-    let addr = get_server_addr();
-    println!("Listening on {}", addr);
+#[derive(sqlx::FromRow)]
+struct DocumentBundle {
+    id: String,
+    documents: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+}
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+/// Future returned by `Storage` methods, boxed so that handlers can hold the
+/// storage as `Arc<dyn Storage>`.
+type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+type StorageResult<'a, T> = StorageFuture<'a, std::result::Result<T, sqlx::Error>>;
 
-    Ok(())
-}
+/// Everything mdow keeps: documents, bundles and the search index. Handlers
+/// only see this trait, so the SQLite files behind it can be swapped for
+/// another backend.
+trait Storage: Send + Sync {
+    fn fetch_document<'a>(&'a self, id: &'a str) -> StorageResult<'a, Option<MarkdownDocument>>;
 
-fn setup_router(pool: SqlitePool) -> Router {
-    Router::new()
-        .route("/", get(handle_main_request))
-        .route("/preview", post(handle_preview_request))
-        .route("/edit", post(handle_edit_request))
-        .route("/share", post(handle_share_request))
-        .route("/view/:id", get(handle_view_request))
-        .fallback(|| async { (StatusCode::NOT_FOUND, handle_404()) })
-        .with_state(pool)
-}
+    fn create_document<'a>(
+        &'a self,
+        doc: &'a NewDocument,
+        content: &'a str,
+        rendered_html: &'a str,
+        source: Option<&'a str>,
+    ) -> StorageResult<'a, ()>;
 
-async fn setup_database() -> Result<SqlitePool> {
-    let db_path = std::env::var("DATABASE_URL").unwrap_or_else(|_| DEFAULT_DB_PATH.to_string());
+    /// Replaces a document's content if `version` is still its current
+    /// version, returning whether the update was applied.
+    fn update_document<'a>(
+        &'a self,
+        id: &'a str,
+        edit_token: &'a str,
+        version: i64,
+        content: &'a str,
+        rendered_html: &'a str,
+    ) -> StorageResult<'a, bool>;
 
-    let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect_with(
-            SqliteConnectOptions::from_str(&db_path)?
-                .create_if_missing(true)
-                .journal_mode(SqliteJournalMode::Wal)
-                .busy_timeout(Duration::from_secs(30)),
-        )
-        .await?;
+    fn delete_document<'a>(&'a self, id: &'a str, delete_token: &'a str)
+        -> StorageResult<'a, bool>;
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS markdown_documents (
-            id TEXT PRIMARY KEY,
-            content TEXT NOT NULL,
-            created_at DATETIME NOT NULL,
-            expires_at DATETIME NOT NULL
-        )
-        "#,
-    )
-    .execute(&pool)
-    .await?;
+    /// Whether a document or a bundle already uses `id`; both are viewed
+    /// under `/view/`, so they share one id space.
+    fn id_exists<'a>(&'a self, id: &'a str) -> StorageResult<'a, bool>;
 
-    Ok(pool)
+    fn fetch_bundle<'a>(&'a self, id: &'a str) -> StorageResult<'a, Option<DocumentBundle>>;
+
+    fn create_bundle<'a>(&'a self, bundle: &'a DocumentBundle) -> StorageResult<'a, ()>;
+
+    /// Counts a view of the document. Failures are only logged, since a
+    /// missed view isn't worth failing the page over.
+    fn record_view<'a>(&'a self, id: &'a str) -> StorageFuture<'a, ()>;
+
+    /// Deletes expired documents and bundles, returning how many went.
+    fn purge_expired(&self) -> StorageFuture<'_, u64>;
+
+    /// Adds a document to the search index. Password-protected documents
+    /// are never indexed, so their content can't leak through snippets.
+    fn index_document<'a>(
+        &'a self,
+        id: &'a str,
+        title: Option<&'a str>,
+        content: &'a str,
+    ) -> StorageResult<'a, ()>;
+
+    fn reindex_document<'a>(
+        &'a self,
+        id: &'a str,
+        title: Option<&'a str>,
+        content: &'a str,
+    ) -> StorageResult<'a, ()>;
+
+    /// The best `SEARCH_RESULT_LIMIT` matches of an FTS5 `query`.
+    fn search<'a>(&'a self, query: &'a str) -> StorageResult<'a, Vec<SearchResult>>;
+
+    fn fetch_stats(&self) -> StorageResult<'_, DocumentStats>;
+
+    /// The `limit` most recently shared documents, newest first.
+    fn fetch_recent_documents(&self, limit: usize) -> StorageResult<'_, Vec<MarkdownDocument>>;
+
+    fn check_health(&self) -> StorageResult<'_, ()>;
+
+    /// Closes the storage, letting SQLite checkpoint its write-ahead log.
+    fn close(&self) -> StorageFuture<'_, ()>;
 }
 
-fn get_server_addr() -> SocketAddr {
-    let port = std::env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(DEFAULT_PORT);
+/// Document storage, either a single SQLite file or several files partitioned
+/// by the first hex character of the document id.
+#[derive(Clone)]
+struct ShardedStorage {
+    shards: Vec<SqlitePool>,
+}
 
-    SocketAddr::from(([0, 0, 0, 0], port))
+impl ShardedStorage {
+    fn shard(&self, id: &str) -> &SqlitePool {
+        &self.shards[shard_index(id, self.shards.len())]
+    }
 }
 
-async fn handle_main_request(params: Option<Query<RenderParams>>) -> impl IntoResponse {
-    let content = params
-        .and_then(|p| p.0.content)
-        .unwrap_or_else(|| "".to_string());
+impl Storage for ShardedStorage {
+    fn fetch_document<'a>(&'a self, id: &'a str) -> StorageResult<'a, Option<MarkdownDocument>> {
+        Box::pin(fetch_markdown_document(self.shard(id), id))
+    }
 
-    let markup = create_markdown_editor_page(&content).await;
-    Html(markup.into_string())
+    fn create_document<'a>(
+        &'a self,
+        doc: &'a NewDocument,
+        content: &'a str,
+        rendered_html: &'a str,
+        source: Option<&'a str>,
+    ) -> StorageResult<'a, ()> {
+        Box::pin(save_markdown_document(
+            self.shard(&doc.id),
+            doc,
+            content,
+            rendered_html,
+            source,
+        ))
+    }
+
+    fn update_document<'a>(
+        &'a self,
+        id: &'a str,
+        edit_token: &'a str,
+        version: i64,
+        content: &'a str,
+        rendered_html: &'a str,
+    ) -> StorageResult<'a, bool> {
+        Box::pin(update_markdown_document(
+            self.shard(id),
+            id,
+            edit_token,
+            version,
+            content,
+            rendered_html,
+        ))
+    }
+
+    fn delete_document<'a>(
+        &'a self,
+        id: &'a str,
+        delete_token: &'a str,
+    ) -> StorageResult<'a, bool> {
+        Box::pin(delete_markdown_document(self.shard(id), id, delete_token))
+    }
+
+    fn id_exists<'a>(&'a self, id: &'a str) -> StorageResult<'a, bool> {
+        Box::pin(document_id_exists(self.shard(id), id))
+    }
+
+    fn fetch_bundle<'a>(&'a self, id: &'a str) -> StorageResult<'a, Option<DocumentBundle>> {
+        Box::pin(fetch_document_bundle(self.shard(id), id))
+    }
+
+    fn create_bundle<'a>(&'a self, bundle: &'a DocumentBundle) -> StorageResult<'a, ()> {
+        Box::pin(save_document_bundle(self.shard(&bundle.id), bundle))
+    }
+
+    fn record_view<'a>(&'a self, id: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(record_document_view(self.shard(id), id))
+    }
+
+    fn purge_expired(&self) -> StorageFuture<'_, u64> {
+        Box::pin(async move {
+            let mut purged = 0;
+            for pool in &self.shards {
+                match purge_expired_documents(pool).await {
+                    Ok(count) => purged += count,
+                    Err(err) => error!("Failed to purge expired documents: {}", err),
+                }
+            }
+            purged
+        })
+    }
+
+    fn index_document<'a>(
+        &'a self,
+        id: &'a str,
+        title: Option<&'a str>,
+        content: &'a str,
+    ) -> StorageResult<'a, ()> {
+        Box::pin(index_document(self.shard(id), id, title, content))
+    }
+
+    fn reindex_document<'a>(
+        &'a self,
+        id: &'a str,
+        title: Option<&'a str>,
+        content: &'a str,
+    ) -> StorageResult<'a, ()> {
+        Box::pin(reindex_document(self.shard(id), id, title, content))
+    }
+
+    fn search<'a>(&'a self, query: &'a str) -> StorageResult<'a, Vec<SearchResult>> {
+        Box::pin(async move {
+            // Each shard returns its own best matches; merge them by rank so
+            // the limit keeps the best matches overall rather than the first
+            // shards'.
+            let mut results = Vec::new();
+            for pool in &self.shards {
+                results.extend(search_documents(pool, query).await?);
+            }
+            results.sort_by(|a, b| a.rank.total_cmp(&b.rank));
+            results.truncate(SEARCH_RESULT_LIMIT);
+            Ok(results)
+        })
+    }
+
+    fn fetch_stats(&self) -> StorageResult<'_, DocumentStats> {
+        Box::pin(async move {
+            let mut stats = DocumentStats::default();
+            for pool in &self.shards {
+                let shard = fetch_document_stats(pool).await?;
+                stats.documents += shard.documents;
+                stats.expiring_within_week += shard.expiring_within_week;
+                stats.stored_bytes += shard.stored_bytes;
+                stats.oldest_created_at = match (stats.oldest_created_at, shard.oldest_created_at) {
+                    (Some(oldest), Some(created_at)) => Some(oldest.min(created_at)),
+                    (oldest, created_at) => oldest.or(created_at),
+                };
+            }
+            Ok(stats)
+        })
+    }
+
+    fn fetch_recent_documents(&self, limit: usize) -> StorageResult<'_, Vec<MarkdownDocument>> {
+        Box::pin(async move {
+            let mut docs = Vec::new();
+            for pool in &self.shards {
+                docs.extend(fetch_recent_markdown_documents(pool, limit).await?);
+            }
+            docs.sort_by_key(|doc| std::cmp::Reverse(doc.created_at));
+            docs.truncate(limit);
+            Ok(docs)
+        })
+    }
+
+    fn check_health(&self) -> StorageResult<'_, ()> {
+        Box::pin(async move {
+            for pool in &self.shards {
+                sqlx::query("SELECT 1").execute(pool).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn close(&self) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            for pool in &self.shards {
+                pool.close().await;
+            }
+        })
+    }
 }
 
-async fn handle_preview_request(Form(input): Form<MarkdownInput>) -> impl IntoResponse {
-    let sanitized_content = clean(&input.content);
-    let html_output = convert_markdown_to_html(&sanitized_content);
+/// Bounds how many markdown renders run at once; callers wait up to
+/// `queue_timeout` for a permit before giving up.
+#[derive(Clone)]
+struct RenderLimiter {
+    permits: Arc<Semaphore>,
+    queue_timeout: Duration,
+}
 
-    let preview_markup = html! {
-        div id="markdown-preview" _="on load call MathJax.typeset()" {
-            br;
-            input type="hidden" name="content" value=(&input.content);
-            (PreEscaped(html_output))
+impl RenderLimiter {
+    fn new(max_concurrent: usize, queue_timeout: Duration) -> Self {
+        RenderLimiter {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            queue_timeout,
         }
-    };
+    }
+
+    async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        tokio::time::timeout(self.queue_timeout, self.permits.acquire())
+            .await
+            .ok()?
+            .ok()
+    }
+}
 
-    Html(preview_markup.into_string())
+/// Rendered HTML of recently viewed documents that were shared before
+/// `rendered_html` was stored alongside the content, so those aren't
+/// converted and sanitized on every view. Documents with stored HTML never
+/// reach the cache and are not counted in its hit and miss metrics. Entries
+/// are keyed by a hash of the content alone, so documents with the same
+/// content share one entry, an edit never sees the old HTML and entries of
+/// deleted documents simply age out. The hash is keyed per process so that
+/// colliding contents can't be crafted.
+#[derive(Clone)]
+struct RenderCache {
+    entries: Option<Cache<u64, Bytes>>,
+    hasher: RandomState,
 }
 
-async fn handle_edit_request(Form(input): Form<MarkdownInput>) -> impl IntoResponse {
-    let edit_markup = html! {
-        textarea id="markdown-input" name="content" placeholder="Enter your markdown..." style="width: 100%; height: calc(100vh - 275px); resize: none;" {
-            (input.content)
+impl RenderCache {
+    fn new(max_bytes: u64) -> Self {
+        let entries = (max_bytes > 0).then(|| {
+            Cache::builder()
+                .max_capacity(max_bytes)
+                .weigher(|_: &u64, html: &Bytes| {
+                    u32::try_from(html.len() + size_of::<u64>()).unwrap_or(u32::MAX)
+                })
+                .build()
+        });
+        RenderCache {
+            entries,
+            hasher: RandomState::new(),
         }
-    };
-    Html(edit_markup.into_string())
+    }
+
+    fn get(&self, content: &str) -> Option<Bytes> {
+        let entries = self.entries.as_ref()?;
+        let html = entries.get(&self.hasher.hash_one(content));
+        match html {
+            Some(_) => METRICS.render_cache_hits.inc(),
+            None => METRICS.render_cache_misses.inc(),
+        }
+        html
+    }
+
+    fn insert(&self, content: &str, html: Bytes) {
+        if let Some(entries) = &self.entries {
+            entries.insert(self.hasher.hash_one(content), html);
+        }
+    }
 }
 
-async fn handle_share_request(
-    State(pool): State<SqlitePool>,
-    Form(input): Form<MarkdownInput>,
-) -> impl IntoResponse {
-    let document_id = generate_short_uuid();
-    let creation_time = Utc::now();
-    let expiration_time = creation_time + chrono::Duration::days(DOCUMENT_EXPIRY_DAYS);
+/// Weak validator for the viewer page. It covers everything that changes
+/// the page, including the "expires in" countdown as it is shown at `now`,
+/// except the view counter, which is allowed to go stale.
+fn create_view_etag(
+    doc: &MarkdownDocument,
+    is_owner: bool,
+    is_editor: bool,
+    now: DateTime<Utc>,
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    (&doc.content, doc.version, &doc.title, &doc.theme).hash(&mut hasher);
+    (is_owner, is_editor).hash(&mut hasher);
+    format_expires_in(doc.expires_at, now).hash(&mut hasher);
+    format!("W/\"{:016x}\"", hasher.finish())
+}
 
-    let sanitized_content = clean(&input.content);
+/// Lets browsers and shared caches keep a viewer page for a short while, and
+/// never past the document's expiry. Pages carrying owner controls or
+/// behind a password are only cached by the browser.
+fn create_view_cache_control(doc: &MarkdownDocument, is_private: bool) -> String {
+    let remaining_secs = doc
+        .expires_at
+        .map_or(VIEW_CACHE_MAX_AGE_SECS, |expires_at| {
+            (expires_at - Utc::now()).num_seconds().max(0) as u64
+        });
+    let max_age = remaining_secs.min(VIEW_CACHE_MAX_AGE_SECS);
+    if is_private || doc.password_hash.is_some() {
+        format!("private, max-age={}", max_age)
+    } else {
+        format!("public, max-age={}", max_age)
+    }
+}
 
-    save_markdown_document(
-        &pool,
-        &document_id,
-        &sanitized_content,
-        creation_time,
-        expiration_time,
-    )
-    .await;
+fn matches_etag(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|candidate| {
+            candidate == "*" || candidate.trim_start_matches("W/") == etag.trim_start_matches("W/")
+        })
+}
 
-    create_htmx_redirect_response(&document_id)
+/// Token bucket per client address: each client may share or import `limit`
+/// documents at once, and regains that allowance evenly over `window`.
+#[derive(Clone)]
+struct ShareRateLimiter {
+    buckets: Arc<Mutex<HashMap<IpAddr, (f64, Instant)>>>,
+    limit: u32,
+    window: Duration,
 }
 
-async fn handle_view_request(
-    State(pool): State<SqlitePool>,
-    Path(id): Path<String>,
-) -> impl IntoResponse {
-    let doc = sqlx::query_as::<_, MarkdownDocument>(
-        "SELECT * FROM markdown_documents WHERE id = ? AND expires_at > datetime('now')",
-    )
-    .bind(id)
-    .fetch_optional(&pool)
-    .await
-    .expect("Failed to fetch document");
+impl ShareRateLimiter {
+    fn new(limit: u32, window: Duration) -> Self {
+        ShareRateLimiter {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            limit,
+            window,
+        }
+    }
 
-    match doc {
-        Some(doc) => {
-            let markup = create_markdown_viewer_page(&doc);
-            Html(markup.into_string())
+    /// Takes a token for `client`, returning `false` when it has none left.
+    fn try_acquire(&self, client: IpAddr) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let capacity = f64::from(self.limit);
+        let refill_per_sec = capacity / self.window.as_secs_f64();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_SHARE_CLIENTS {
+            buckets.retain(|_, (_, updated)| now.duration_since(*updated) < self.window);
+        }
+
+        let (tokens, updated) = buckets.entry(client).or_insert((capacity, now));
+        *tokens =
+            (*tokens + now.duration_since(*updated).as_secs_f64() * refill_per_sec).min(capacity);
+        *updated = now;
+        if *tokens < 1.0 {
+            return false;
         }
-        None => handle_404(),
+        *tokens -= 1.0;
+        true
     }
 }
 
-async fn handle_debug_request(State(pool): State<SqlitePool>) -> impl IntoResponse {
-    let docs = sqlx::query_as::<_, MarkdownDocument>(
-        "SELECT * FROM markdown_documents ORDER BY created_at DESC LIMIT 5",
-    )
-    .fetch_all(&pool)
-    .await
-    .unwrap_or_default();
+#[derive(Clone)]
+struct AppState {
+    storage: Arc<dyn Storage>,
+    config: Arc<Config>,
+    render_limiter: RenderLimiter,
+    render_cache: RenderCache,
+    share_rate_limiter: ShareRateLimiter,
+}
 
-    let debug_markup = html! {
-        div {
-            h2 { "Recent Documents" }
-            @for doc in docs {
-                div style="margin-bottom: 2ch; padding: 1ch; border: 1px solid #ccc;" {
-                    p { "ID: " (doc.id) }
-                    p { "Created: " (doc.created_at.format("%Y-%m-%d")) }
-                    p { "Expires: " (doc.expires_at.format("%Y-%m-%d")) }
-                    p { "Content: " (doc.content) }
-                }
-            }
-        }
-    };
+impl FromRef<AppState> for Arc<dyn Storage> {
+    fn from_ref(state: &AppState) -> Self {
+        state.storage.clone()
+    }
+}
 
-    Html(debug_markup.into_string())
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
 }
 
-fn handle_404() -> Html<String> {
-    Html(
-        html! {
-            (create_html_head(Some("404")));
-            body a="auto" {
-                main class="content" aria-label="Content" {
-                    div class="w" {
-                        h1 { "404 - Page Not Found" }
-                        p { "The page you're looking for doesn't exist." }
-                        p { a href="/" { "Return to homepage" } }
-                    }
-                }
-            }
-            (create_page_footer());
-        }
-        .into_string(),
-    )
+impl FromRef<AppState> for RenderLimiter {
+    fn from_ref(state: &AppState) -> Self {
+        state.render_limiter.clone()
+    }
 }
 
-async fn save_markdown_document(
-    pool: &SqlitePool,
-    id: &str,
-    content: &str,
-    created_at: DateTime<Utc>,
-    expires_at: DateTime<Utc>,
-) {
-    sqlx::query(
-        r#"
-        INSERT INTO markdown_documents (id, content, created_at, expires_at)
-        VALUES (?, ?, ?, ?)
-        "#,
-    )
-    .bind(id)
-    .bind(content)
-    .bind(created_at)
-    .bind(expires_at)
-    .execute(pool)
-    .await
-    .expect("Failed to save document");
+impl FromRef<AppState> for RenderCache {
+    fn from_ref(state: &AppState) -> Self {
+        state.render_cache.clone()
+    }
 }
 
-fn convert_markdown_to_html(markdown_content: &str) -> String {
-    let markdown_options = set_markdown_parser_options();
-    let parser = Parser::new_ext(markdown_content, markdown_options);
-    let mut html_output = String::new();
-    push_html(&mut html_output, parser);
+impl FromRef<AppState> for ShareRateLimiter {
+    fn from_ref(state: &AppState) -> Self {
+        state.share_rate_limiter.clone()
+    }
+}
 
-    add_syntax_highlighting_containers(html_output)
+/// Deployment settings, read once from the environment at startup.
+struct Config {
+    markdown_options: Options,
+    max_expiry_days: Option<i64>,
+    default_expiry_days: i64,
+    max_nesting_depth: usize,
+    max_content_bytes: usize,
+    id_length: usize,
+    external_link_indicators: bool,
+    maintenance_mode: bool,
+    require_terms: bool,
+    terms_url: Option<String>,
+    security_headers: Vec<(HeaderName, HeaderValue)>,
+    assets_dir: Option<PathBuf>,
+    base_url: String,
+    qr_error_correction: EcLevel,
+    metrics_enabled: bool,
+    highlight_theme: String,
+    search_enabled: bool,
+    allow_indexing: bool,
+    stats_token: Option<String>,
+    cors_allowed_origins: Vec<HeaderValue>,
+    cors_allowed_methods: Vec<Method>,
+    cors_allowed_headers: Vec<HeaderName>,
+    client_ip_header: Option<HeaderName>,
 }
 
-fn set_markdown_parser_options() -> Options {
-    let mut options = Options::empty();
-    options.insert(Options::ENABLE_TABLES);
-    options.insert(Options::ENABLE_STRIKETHROUGH);
-    options.insert(Options::ENABLE_TASKLISTS);
-    options
+impl Config {
+    fn from_env() -> std::result::Result<Self, String> {
+        Ok(Config {
+            markdown_options: get_markdown_options(),
+            max_expiry_days: get_max_expiry_days(),
+            default_expiry_days: get_default_expiry_days(),
+            max_nesting_depth: get_max_nesting_depth(),
+            max_content_bytes: get_max_content_bytes(),
+            id_length: get_id_length(),
+            external_link_indicators: get_env_flag("EXTERNAL_LINK_INDICATORS", false),
+            maintenance_mode: get_env_flag("MAINTENANCE_MODE", false),
+            require_terms: get_env_flag("REQUIRE_TERMS", false),
+            terms_url: std::env::var("TERMS_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            security_headers: get_security_headers(),
+            assets_dir: get_assets_dir(),
+            base_url: get_base_url(),
+            qr_error_correction: get_qr_error_correction(),
+            metrics_enabled: get_env_flag("METRICS_ENABLED", false),
+            highlight_theme: get_highlight_theme()?,
+            search_enabled: get_env_flag("SEARCH_ENABLED", false),
+            allow_indexing: get_env_flag("ALLOW_INDEXING", true),
+            stats_token: std::env::var("STATS_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            cors_allowed_origins: get_env_list("CORS_ALLOWED_ORIGINS", ""),
+            cors_allowed_methods: get_env_list("CORS_ALLOWED_METHODS", "GET,POST"),
+            cors_allowed_headers: get_env_list("CORS_ALLOWED_HEADERS", "content-type"),
+            client_ip_header: get_client_ip_header(),
+        })
+    }
 }
 
-fn add_syntax_highlighting_containers(html: String) -> String {
-    html.replace("<pre>", "<div class=\"highlighter-rouge\"><pre>")
-        .replace("</pre>", "</pre></div>")
+/// Operational counters, exposed at `/metrics` in the Prometheus text format
+/// when `METRICS_ENABLED` is set.
+struct Metrics {
+    registry: Registry,
+    documents_created: IntCounter,
+    documents_viewed: IntCounter,
+    not_found: IntCounter,
+    render_duration: Histogram,
+    render_cache_hits: IntCounter,
+    render_cache_misses: IntCounter,
 }
 
-fn extract_title_from_html(html_content: &str) -> Option<&str> {
-    html_content.find("<h1>").and_then(|start| {
-        html_content[start..]
-            .find("</h1>")
-            .map(|end| &html_content[start + 4..start + end])
-    })
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let documents_created =
+            IntCounter::new("mdow_documents_created_total", "Documents shared").unwrap();
+        let documents_viewed =
+            IntCounter::new("mdow_documents_viewed_total", "Document page views").unwrap();
+        let not_found =
+            IntCounter::new("mdow_not_found_total", "Responses with status 404").unwrap();
+        let render_duration = Histogram::with_opts(HistogramOpts::new(
+            "mdow_render_duration_seconds",
+            "Time spent converting markdown to HTML",
+        ))
+        .unwrap();
+        let render_cache_hits = IntCounter::new(
+            "mdow_render_cache_hits_total",
+            "Views of documents without stored HTML served from the render cache",
+        )
+        .unwrap();
+        let render_cache_misses = IntCounter::new(
+            "mdow_render_cache_misses_total",
+            "Views of documents without stored HTML that had to render them",
+        )
+        .unwrap();
+        registry
+            .register(Box::new(documents_created.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(documents_viewed.clone()))
+            .unwrap();
+        registry.register(Box::new(not_found.clone())).unwrap();
+        registry
+            .register(Box::new(render_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(render_cache_hits.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(render_cache_misses.clone()))
+            .unwrap();
+        Metrics {
+            registry,
+            documents_created,
+            documents_viewed,
+            not_found,
+            render_duration,
+            render_cache_hits,
+            render_cache_misses,
+        }
+    }
 }
 
-fn create_html_head(page_title: Option<&str>) -> Markup {
-    html! {
-        head {
-            title { (page_title.unwrap_or("mdow")) };
+/// Where pages load their stylesheet and scripts from. Every URL can be
+/// overridden so that air-gapped deployments can serve their own copies,
+/// for example from `ASSETS_DIR` under `/assets/`. Analytics are left out
+/// entirely when `ANALYTICS_ENABLED` is off.
+struct AssetUrls {
+    stylesheet: String,
+    mathjax: String,
+    htmx: String,
+    hyperscript: String,
+    mermaid: String,
+    goatcounter: Option<String>,
+    goatcounter_script: String,
+}
 
-            meta charset="utf-8";
-            meta name="viewport" content="width=device-width, initial-scale=1";
+impl AssetUrls {
+    fn from_env() -> Self {
+        AssetUrls {
+            stylesheet: get_asset_url("STYLESHEET_URL", DEFAULT_STYLESHEET_URL),
+            mathjax: get_asset_url("MATHJAX_URL", DEFAULT_MATHJAX_URL),
+            htmx: get_asset_url("HTMX_URL", DEFAULT_HTMX_URL),
+            hyperscript: get_asset_url("HYPERSCRIPT_URL", DEFAULT_HYPERSCRIPT_URL),
+            mermaid: get_asset_url("MERMAID_URL", DEFAULT_MERMAID_URL),
+            goatcounter: get_env_flag("ANALYTICS_ENABLED", true)
+                .then(|| {
+                    std::env::var("GOATCOUNTER_URL")
+                        .unwrap_or_else(|_| DEFAULT_GOATCOUNTER_URL.to_string())
+                        .split_whitespace()
+                        .next()
+                        .map(str::to_string)
+                })
+                .flatten(),
+            goatcounter_script: get_asset_url(
+                "GOATCOUNTER_SCRIPT_URL",
+                DEFAULT_GOATCOUNTER_SCRIPT_URL,
+            ),
+        }
+    }
 
-            meta name="title" content="mdow 🌾 | markdown on web";
-            meta name="description" content="A meadow for your markdown on web. A lightweight, browser-based markdown editor and previewer that makes sharing markdown files as simple as sharing a link.";
-            meta name="keywords" content="markdown editor, online markdown, markdown preview, markdown sharing, web markdown, browser markdown";
+    /// Content-Security-Policy allowing exactly the origins the assets are
+    /// loaded from. Assets served from a local path are covered by `'self'`.
+    fn content_security_policy(&self) -> String {
+        let sources = |urls: &[&str]| {
+            let mut sources = vec!["'self'".to_string()];
+            for origin in urls.iter().filter_map(|url| asset_origin(url)) {
+                if !sources.contains(&origin) {
+                    sources.push(origin);
+                }
+            }
+            sources.join(" ")
+        };
+        let goatcounter = self.goatcounter.as_deref().unwrap_or_default();
+        let mut scripts = vec![
+            self.mathjax.as_str(),
+            &self.htmx,
+            &self.hyperscript,
+            &self.mermaid,
+        ];
+        if self.goatcounter.is_some() {
+            scripts.push(&self.goatcounter_script);
+        }
+        format!(
+            "default-src 'self'; script-src {}; style-src {} 'unsafe-inline'; \
+             font-src {}; img-src 'self' data: https:; connect-src {}; \
+             frame-ancestors 'none'; base-uri 'self'; form-action 'self'",
+            sources(&scripts),
+            sources(&[&self.stylesheet]),
+            sources(&[&self.mathjax, &self.stylesheet]),
+            sources(&[goatcounter]),
+        )
+    }
+}
 
-            meta name="application-name" content="mdow";
-            meta name="mobile-web-app-capable" content="yes";
-            meta name="apple-mobile-web-app-capable" content="yes";
-            meta name="apple-mobile-web-app-title" content="mdow";
-            meta name="apple-mobile-web-app-status-bar-style" content="default";
-            meta name="theme-color" content="#ffffff" media="(prefers-color-scheme: light)";
-            meta name="theme-color" content="#000000" media="(prefers-color-scheme: dark)";
+/// Sets of pulldown-cmark extensions selectable through `MARKDOWN_PRESET`.
+#[derive(Clone, Copy, Default)]
+enum MarkdownPreset {
+    #[default]
+    Default,
+    Full,
+    Commonmark,
+}
 
-            link rel="apple-touch-icon" href="data:image/svg+xml,<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 100 100'><text y='.9em' font-size='90'>🌾</text></svg>";
+impl FromStr for MarkdownPreset {
+    type Err = String;
 
-            link rel="icon" href="data:image/svg+xml,<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 100 100'><text y='.9em' font-size='90'>🌾</text></svg>";
-            link rel="stylesheet" href="https://yree.io/mold/assets/css/main.css";
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "default" => Ok(MarkdownPreset::Default),
+            "full" => Ok(MarkdownPreset::Full),
+            "commonmark" => Ok(MarkdownPreset::Commonmark),
+            other => Err(format!("unknown markdown preset '{}'", other)),
+        }
+    }
+}
+
+/// Color scheme a document is displayed in, stored per document.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Theme {
+    #[default]
+    Auto,
+    Light,
+    Dark,
+}
 
-            script src="https://cdn.jsdelivr.net/npm/mathjax@3/es5/tex-mml-chtml.js" async="" {};
-            script src="https://unpkg.com/htmx.org@1.9.10" {};
-            script src="https://unpkg.com/hyperscript.org@0.9.12" {};
+impl Theme {
+    const ALL: [Theme; 3] = [Theme::Auto, Theme::Light, Theme::Dark];
 
-            script data-goatcounter="https://yree.goatcounter.com/count" async src="//gc.zgo.at/count.js" {};
+    fn as_str(self) -> &'static str {
+        match self {
+            Theme::Auto => "auto",
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Theme::Auto => "Match the reader's system",
+            Theme::Light => "Light",
+            Theme::Dark => "Dark",
         }
     }
 }
 
-fn create_page_footer() -> Markup {
-    html! {
-        footer {
-            div class="w" {
-                p { a href="https://yree.io/mdow" { "mdow" } " 🌾 :: a " a href="https://yree.io" { "Yree" } " product ♥" }
-            }
+impl FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(Theme::Auto),
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            other => Err(format!("unknown theme '{}'", other)),
         }
     }
 }
 
-async fn create_markdown_editor_page(initial_content: &str) -> Markup {
-    html! {
-        (create_html_head(None));
-        body a="auto" {
-            main class="content" aria-label="Content" {
-                div class="w" {
-                    h1 { "mdow 🌾" }
-                    p { dfn {"A meadow for your " b {"markdown on web."} } }
-                    p { "Enter your markdown, preview it, and share it." }
-                    div class="grid" {
-                        button
-                            id="preview-button"
-                            hx-post="/preview"
-                            hx-trigger="click"
-                            hx-target="#markdown-input"
-                            hx-swap="outerHTML"
-                            hx-include="#markdown-input"
-                            hx-validate="true"
-                            hx-disabled-elt="this"
-                            _="on htmx:afterRequest
-                               hide me
-                               show #edit-button"
-                               { "Preview" }
-                        button
-                            id="edit-button"
-                            hx-post="/edit"
-                            hx-trigger="click"
-                            hx-target="#markdown-preview"
-                            hx-swap="outerHTML"
-                            hx-include="#markdown-preview"
-                            style="display: none;"
-                            hx-disabled-elt="this"
-                            _="on htmx:afterRequest
-                               hide me
-                               show #preview-button"
-                               { "Edit" }
-                        button
-                            id="share-button"
-                            hx-post="/share"
-                            hx-trigger="click"
-                            hx-include="[name='content']"
-                            hx-validate="true"
-                            hx-disabled-elt="this"
-                            { "Share" }
-                    }
-                    textarea
-                        id="markdown-input"
-                        name="content"
-                        placeholder=(if initial_content.is_empty() { "Enter your markdown..." } else { "" })
-                        style="width: 100%; height: calc(100vh - 275px); resize: none;"
-                        required="required"
-                        _=(if initial_content.is_empty() {
-                            "on load
-                                set my.value to (localStorage.getItem('markdownContent'))
-                             on input
-                                wait 500ms then
-                                call localStorage.setItem('markdownContent', my.value)"
-                        } else {
-                            "on input
-                                wait 500ms then
-                                call localStorage.setItem('markdownContent', my.value)"
-                        })
-                        { (initial_content) }
-                }
-            }
+#[derive(Serialize)]
+struct DocumentResponse {
+    id: String,
+    content: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    theme: String,
+    title: Option<String>,
+}
+
+impl From<MarkdownDocument> for DocumentResponse {
+    fn from(doc: MarkdownDocument) -> Self {
+        DocumentResponse {
+            id: doc.id,
+            content: doc.content,
+            created_at: doc.created_at,
+            expires_at: doc.expires_at,
+            theme: doc.theme,
+            title: doc.title,
         }
-        (create_page_footer());
     }
 }
 
-fn create_markdown_viewer_page(doc: &MarkdownDocument) -> Markup {
-    let html_output = convert_markdown_to_html(&doc.content);
-    let page_title = extract_title_from_html(&html_output);
+#[derive(Deserialize)]
+struct ApiShareInput {
+    content: String,
+    expiry_days: Option<i64>,
+    never_expires: Option<bool>,
+    source: Option<String>,
+    theme: Option<String>,
+    password: Option<String>,
+    title: Option<String>,
+    indexable: Option<bool>,
+    terms_accepted: Option<bool>,
+}
 
-    html! {
-        (create_html_head(page_title));
-        body a="auto" {
-            main class="content" aria-label="Content" {
-                div class="w" id="markdown-view" _="on load call MathJax.typeset()" {
-                    (PreEscaped(html_output))
-                }
+/// Choices made at share time, from the editor form or the JSON API.
+struct ShareOptions {
+    expiry_days: Option<i64>,
+    never_expires: bool,
+    source: Option<String>,
+    theme: Theme,
+    password: Option<String>,
+    title: Option<String>,
+    indexable: bool,
+}
+
+#[derive(Serialize)]
+struct ShareResponse {
+    id: String,
+    url: String,
+    expires_at: Option<DateTime<Utc>>,
+    delete_token: String,
+    edit_token: String,
+}
+
+/// A freshly stored document along with the secrets needed to edit or delete it.
+struct NewDocument {
+    id: String,
+    delete_token: String,
+    edit_token: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    theme: Theme,
+    password_hash: Option<String>,
+    unlock_token: Option<String>,
+    title: Option<String>,
+    indexable: bool,
+}
+
+/// Error body shared by the JSON routes, sent with the status it names.
+#[derive(Serialize)]
+struct ApiError {
+    code: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code: status.as_u16(),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<AppError> for ApiError {
+    fn from(err: AppError) -> Self {
+        err.log();
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        AppError::from(err).into()
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, Json(self)).into_response()
+    }
+}
+
+/// Failures a handler can't recover from, rendered as a 500 page instead of
+/// taking the worker down.
+#[derive(Debug)]
+enum AppError {
+    Database(sqlx::Error),
+    PasswordHash(password_hash::Error),
+}
+
+impl AppError {
+    fn log(&self) {
+        match self {
+            AppError::Database(err) => error!("Database error: {}", err),
+            AppError::PasswordHash(err) => error!("Failed to hash password: {}", err),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        AppError::Database(err)
+    }
+}
+
+impl From<password_hash::Error> for AppError {
+    fn from(err: password_hash::Error) -> Self {
+        AppError::PasswordHash(err)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        self.log();
+        (StatusCode::INTERNAL_SERVER_ERROR, handle_500()).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct ImportInput {
+    url: String,
+}
+
+/// Reasons an import is refused, reported to the editor as plain text.
+#[derive(Debug)]
+enum ImportError {
+    InvalidUrl,
+    UnknownHost,
+    ForbiddenAddress,
+    Fetch(reqwest::Error),
+    Status(u16),
+    TooLarge(usize),
+    NotText,
+}
+
+impl From<reqwest::Error> for ImportError {
+    fn from(err: reqwest::Error) -> Self {
+        ImportError::Fetch(err)
+    }
+}
+
+impl IntoResponse for ImportError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ImportError::InvalidUrl => (
+                StatusCode::BAD_REQUEST,
+                "Enter an http or https URL".to_string(),
+            ),
+            ImportError::UnknownHost => (
+                StatusCode::BAD_GATEWAY,
+                "Could not find that host".to_string(),
+            ),
+            ImportError::ForbiddenAddress => (
+                StatusCode::FORBIDDEN,
+                "That address can't be imported from".to_string(),
+            ),
+            ImportError::Fetch(err) => {
+                warn!("Import failed: {}", err);
+                (
+                    StatusCode::BAD_GATEWAY,
+                    "Could not fetch that URL".to_string(),
+                )
             }
-            footer {
-                div class="w grid" {
-                    (PreEscaped(generate_qr_svg(&doc.id)))
-                    div {
-                        p {
-                            "created on " (doc.created_at.format("%Y-%m-%d"))
-                        }
-                        p {
-                            a href=(format!("/?content={}", urlencoding::encode(&doc.content))) { "edit" }
-                            " in "
-                            a href="/" { "mdow" }
-                            " 🌾"
-                        }
-                    }
-                }
+            ImportError::Status(status) => (
+                StatusCode::BAD_GATEWAY,
+                format!("The server answered with status {}", status),
+            ),
+            ImportError::TooLarge(max_bytes) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("The file is larger than {}", format_bytes(max_bytes)),
+            ),
+            ImportError::NotText => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The file is not UTF-8 text".to_string(),
+            ),
+        };
+        (status, message).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct UpdateInput {
+    content: String,
+    version: Option<i64>,
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UnlockInput {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct DeleteInput {
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ForkInput {
+    terms_accepted: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RenderParams {
+    content: Option<String>,
+    template: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+}
+
+#[derive(Deserialize)]
+struct StatsParams {
+    format: Option<String>,
+}
+
+/// Totals over the live documents of every shard, shown at `/stats`.
+#[derive(Serialize, Default, sqlx::FromRow)]
+struct DocumentStats {
+    documents: i64,
+    expiring_within_week: i64,
+    stored_bytes: i64,
+    oldest_created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct SearchResult {
+    id: String,
+    title: Option<String>,
+    snippet: String,
+    /// FTS5 rank of the match; lower is better.
+    #[serde(skip)]
+    rank: f64,
+}
+
+#[derive(Deserialize)]
+struct ShareParams {
+    format: Option<String>,
+}
+
+/// Built-in starter documents that can be loaded with `/?template=<name>`.
+const TEMPLATES: &[(&str, &str, &str)] = &[
+    (
+        "meeting-notes",
+        "meeting notes",
+        "# Meeting notes\n\n**Date:** \n**Attendees:** \n\n## Agenda\n\n1. \n\n## Notes\n\n- \n\n## Action items\n\n- [ ] \n",
+    ),
+    (
+        "readme",
+        "readme",
+        "# Project name\n\nA short description of what this project does.\n\n## Installation\n\n```bash\n\n```\n\n## Usage\n\n```bash\n\n```\n\n## License\n\n",
+    ),
+    (
+        "blog-post",
+        "blog post",
+        "# Post title\n\n_A one-line summary of the post._\n\n## Introduction\n\n\n## Main points\n\n\n## Conclusion\n\n",
+    ),
+];
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER)),
+        )
+        .init();
+
+    let config = Arc::new(Config::from_env()?);
+    let storage: Arc<dyn Storage> = Arc::new(setup_storage().await?);
+    if get_env_flag("PURGE_EXPIRED", true) {
+        tokio::spawn(purge_expired_documents_periodically(
+            storage.clone(),
+            config.clone(),
+            get_purge_interval(),
+        ));
+    }
+    let render_limiter = RenderLimiter::new(get_render_concurrency(), get_render_queue_timeout());
+    let render_cache = RenderCache::new(get_render_cache_bytes());
+    let share_rate_limiter = ShareRateLimiter::new(get_share_rate_limit(), get_share_rate_window());
+    let app = setup_router(AppState {
+        storage: storage.clone(),
+        config,
+        render_limiter,
+        render_cache,
+        share_rate_limiter,
+    });
+    let addr = get_server_addr();
+    info!("Listening on {}", addr);
+
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    info!("Shutting down, closing database connections");
+    storage.close().await;
+
+    Ok(())
+}
+
+/// Resolves on ctrl-c or SIGTERM, letting in-flight requests finish before
+/// the server stops.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+fn setup_router(state: AppState) -> Router {
+    let share_rate_limit = || middleware::from_fn_with_state(state.clone(), limit_share_rate);
+    let maintenance =
+        || middleware::from_fn_with_state(state.clone(), reject_writes_during_maintenance);
+    let write_routes = Router::new()
+        .route(
+            "/share",
+            post(handle_share_request).route_layer(share_rate_limit()),
+        )
+        .route(
+            "/fork/:id",
+            post(handle_fork_request).route_layer(share_rate_limit()),
+        )
+        .route(
+            "/share-bundle",
+            post(handle_share_bundle_request).route_layer(share_rate_limit()),
+        )
+        .route("/update/:id", post(handle_update_request))
+        .route("/delete/:id", post(handle_delete_request))
+        .route_layer(maintenance());
+
+    let mut api_routes = Router::new()
+        .route(
+            "/api/share",
+            post(handle_api_share_request)
+                .route_layer(share_rate_limit())
+                .route_layer(maintenance()),
+        )
+        .route("/api/view/:id", get(handle_api_view_request));
+    if let Some(cors) = create_cors_layer(&state.config) {
+        api_routes = api_routes.layer(cors);
+    }
+
+    let mut router = Router::new()
+        .route("/", get(handle_main_request))
+        .route("/preview", post(handle_preview_request))
+        .route("/edit", post(handle_edit_request))
+        .route(
+            "/import",
+            post(handle_import_request).route_layer(share_rate_limit()),
+        )
+        .route("/bundle", get(handle_bundle_editor_request))
+        .merge(write_routes)
+        .merge(api_routes)
+        .route("/view/:id", get(handle_view_request))
+        .route("/view/:id/unlock", post(handle_unlock_request))
+        .route("/update/:id", get(handle_update_page_request))
+        .route("/view/:id/card.svg", get(handle_card_request))
+        .route("/raw/:id", get(handle_raw_request))
+        .route("/download/:id", get(handle_download_request))
+        .route("/fragment/:id", get(handle_fragment_request))
+        .route("/health", get(handle_health_request))
+        .route("/robots.txt", get(handle_robots_request))
+        .route("/assets/*path", get(handle_asset_request));
+    if state.config.metrics_enabled {
+        router = router.route("/metrics", get(handle_metrics_request));
+    }
+    if state.config.search_enabled {
+        router = router.route("/search", get(handle_search_request));
+    }
+    if state.config.stats_token.is_some() {
+        router = router.merge(
+            Router::new()
+                .route("/stats", get(handle_stats_request))
+                .route("/stats/documents", get(handle_recent_documents_request))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    require_stats_token,
+                )),
+        );
+    }
+
+    router
+        .fallback(|| async { (StatusCode::NOT_FOUND, handle_404()) })
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(request_body_limit(
+            state.config.max_content_bytes,
+        )))
+        .layer(middleware::from_fn(count_not_found))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            add_security_headers,
+        ))
+        .layer(
+            CompressionLayer::new()
+                .compress_when(DefaultPredicate::new().and(SizeAbove::new(COMPRESSION_MIN_BYTES))),
+        )
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .with_state(state)
+}
+
+async fn setup_storage() -> Result<ShardedStorage> {
+    let db_path = std::env::var("DATABASE_URL")
+        .map(|url| normalize_database_url(&url))
+        .unwrap_or_else(|_| DEFAULT_DB_PATH.to_string());
+    let shard_count = get_shard_count();
+
+    let mut shards = Vec::with_capacity(shard_count);
+    for index in 0..shard_count {
+        let shard_path = if shard_count == 1 {
+            db_path.clone()
+        } else {
+            get_shard_db_path(&db_path, index)
+        };
+        shards.push(setup_database(&shard_path).await?);
+    }
+
+    Ok(ShardedStorage { shards })
+}
+
+/// Reads the enabled markdown extensions from `MD_OPTIONS`, falling back to
+/// the extensions of `MARKDOWN_PRESET` when it isn't set.
+fn get_markdown_options() -> Options {
+    match std::env::var("MD_OPTIONS") {
+        Ok(names) => parse_markdown_options(&names),
+        Err(_) => set_markdown_parser_options(get_markdown_preset()),
+    }
+}
+
+/// Builds an `Options` bitset from a comma-separated list of extension names,
+/// logging and skipping names it doesn't know.
+fn parse_markdown_options(names: &str) -> Options {
+    let mut options = Options::empty();
+    for name in names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        match name.to_ascii_lowercase().as_str() {
+            "tables" => options.insert(Options::ENABLE_TABLES),
+            "strikethrough" => options.insert(Options::ENABLE_STRIKETHROUGH),
+            "tasklists" => options.insert(Options::ENABLE_TASKLISTS),
+            "footnotes" => options.insert(Options::ENABLE_FOOTNOTES),
+            "smart" | "smart_punctuation" => options.insert(Options::ENABLE_SMART_PUNCTUATION),
+            "heading_attributes" => options.insert(Options::ENABLE_HEADING_ATTRIBUTES),
+            other => warn!("Ignoring unknown markdown option '{}'", other),
+        }
+    }
+    options
+}
+
+fn get_allowed_html_tags() -> Vec<&'static str> {
+    std::env::var("ALLOWED_HTML_TAGS")
+        .map(|names| parse_allowed_html_tags(&names))
+        .unwrap_or_default()
+}
+
+/// Picks the optional elements named in a comma-separated list, logging and
+/// skipping names that aren't on the list of harmless ones.
+fn parse_allowed_html_tags(names: &str) -> Vec<&'static str> {
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| {
+            let tag = OPTIONAL_HTML_TAGS
+                .into_iter()
+                .find(|tag| tag.eq_ignore_ascii_case(name));
+            if tag.is_none() {
+                warn!("Ignoring HTML tag '{}' that can't be allowed", name);
             }
+            tag
+        })
+        .collect()
+}
+
+fn get_markdown_preset() -> MarkdownPreset {
+    match std::env::var("MARKDOWN_PRESET") {
+        Ok(preset) => preset.parse().unwrap_or_else(|err| {
+            warn!("{}, using the default preset", err);
+            MarkdownPreset::Default
+        }),
+        Err(_) => MarkdownPreset::Default,
+    }
+}
+
+fn get_max_expiry_days() -> Option<i64> {
+    std::env::var("MAX_EXPIRY_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .filter(|&days| days > 0)
+}
+
+/// How long documents live when the author doesn't pick an expiry, from
+/// `DEFAULT_EXPIRY_DAYS`.
+fn get_default_expiry_days() -> i64 {
+    let Ok(value) = std::env::var("DEFAULT_EXPIRY_DAYS") else {
+        return DOCUMENT_EXPIRY_DAYS;
+    };
+    match value.trim().parse() {
+        Ok(days) if (MIN_EXPIRY_DAYS..=MAX_REQUESTED_EXPIRY_DAYS).contains(&days) => days,
+        _ => {
+            warn!(
+                "Invalid DEFAULT_EXPIRY_DAYS '{}', using {} days",
+                value, DOCUMENT_EXPIRY_DAYS
+            );
+            DOCUMENT_EXPIRY_DAYS
         }
     }
 }
 
-fn create_htmx_redirect_response(document_id: &str) -> impl IntoResponse {
-    let mut headers = axum::http::HeaderMap::new();
-    headers.insert(
-        "hx-redirect",
-        format!("/view/{}", document_id).parse().unwrap(),
-    );
-    (headers, "")
+fn get_purge_interval() -> Duration {
+    let secs = std::env::var("PURGE_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(DEFAULT_PURGE_INTERVAL_SECS);
+    Duration::from_secs(secs)
+}
+
+fn get_env_flag(name: &str, default: bool) -> bool {
+    match std::env::var(name) {
+        Ok(value) => matches!(
+            value.trim().to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        ),
+        Err(_) => default,
+    }
+}
+
+fn get_max_content_bytes() -> usize {
+    std::env::var("MAX_CONTENT_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .filter(|&bytes| bytes > 0)
+        .unwrap_or(DEFAULT_MAX_CONTENT_BYTES)
+}
+
+/// Largest request body accepted, rejected with `413` before any handler
+/// buffers it. Form encoding can triple the size of the content, and the
+/// other share fields need a little room on top.
+fn request_body_limit(max_content_bytes: usize) -> usize {
+    max_content_bytes
+        .saturating_mul(3)
+        .saturating_add(BODY_LIMIT_SLACK_BYTES)
+}
+
+fn get_id_length() -> usize {
+    parse_id_length(std::env::var("ID_LENGTH").ok().as_deref())
+}
+
+/// Parses an id length, clamped to what `generate_short_uuid` can produce
+/// and still resist guessing.
+fn parse_id_length(value: Option<&str>) -> usize {
+    value
+        .and_then(|length| length.trim().parse().ok())
+        .unwrap_or(SHORT_ID_LENGTH)
+        .clamp(MIN_ID_LENGTH, MAX_ID_LENGTH)
 }
 
-fn generate_short_uuid() -> String {
-    Uuid::new_v4().to_string()[..7].to_string()
+fn get_max_nesting_depth() -> usize {
+    std::env::var("MAX_NESTING_DEPTH")
+        .ok()
+        .and_then(|depth| depth.parse().ok())
+        .filter(|&depth| depth > 0)
+        .unwrap_or(DEFAULT_MAX_NESTING_DEPTH)
+}
+
+fn get_render_concurrency() -> usize {
+    std::env::var("RENDER_CONCURRENCY")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+}
+
+fn get_render_queue_timeout() -> Duration {
+    let millis = std::env::var("RENDER_QUEUE_TIMEOUT_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .unwrap_or(DEFAULT_RENDER_QUEUE_TIMEOUT_MS);
+    Duration::from_millis(millis)
+}
+
+fn get_share_rate_limit() -> u32 {
+    std::env::var("SHARE_RATE_LIMIT")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_SHARE_RATE_LIMIT)
+}
+
+fn get_render_cache_bytes() -> u64 {
+    std::env::var("RENDER_CACHE_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(DEFAULT_RENDER_CACHE_BYTES)
+}
+
+fn get_share_rate_window() -> Duration {
+    let secs = std::env::var("SHARE_RATE_WINDOW_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(DEFAULT_SHARE_RATE_WINDOW_SECS);
+    Duration::from_secs(secs)
 }
 
-fn generate_qr_svg(id: &str) -> String {
-    let url = format!("https://mdow.yree.io/view/{}", id);
-    let code = QrCode::new(url).expect("Failed to generate QR code");
-    let svg = code.render::<svg::Color>().min_dimensions(64, 64).build();
-    svg
+/// Headers added to every response. Each can be replaced through its
+/// environment variable, or dropped by setting that variable to an empty
+/// string.
+fn get_security_headers() -> Vec<(HeaderName, HeaderValue)> {
+    [
+        (
+            CONTENT_SECURITY_POLICY,
+            "CONTENT_SECURITY_POLICY",
+            ASSET_URLS.content_security_policy(),
+        ),
+        (
+            REFERRER_POLICY,
+            "REFERRER_POLICY",
+            DEFAULT_REFERRER_POLICY.to_string(),
+        ),
+        (
+            X_FRAME_OPTIONS,
+            "X_FRAME_OPTIONS",
+            DEFAULT_X_FRAME_OPTIONS.to_string(),
+        ),
+        (
+            X_CONTENT_TYPE_OPTIONS,
+            "X_CONTENT_TYPE_OPTIONS",
+            "nosniff".to_string(),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(header, variable, default)| {
+        let value = std::env::var(variable).unwrap_or_else(|_| default.clone());
+        if value.trim().is_empty() {
+            return None;
+        }
+        match HeaderValue::from_str(&value) {
+            Ok(value) => Some((header, value)),
+            Err(_) => {
+                warn!("Invalid {} value, using the default", variable);
+                HeaderValue::from_str(&default)
+                    .ok()
+                    .map(|value| (header, value))
+            }
+        }
+    })
+    .collect()
+}
+
+/// Header a trusted proxy puts the client address in, such as
+/// `Fly-Client-IP` or `X-Forwarded-For`. Only set this behind a proxy that
+/// overwrites the header, since clients can send it themselves.
+fn get_client_ip_header() -> Option<HeaderName> {
+    let value = std::env::var("CLIENT_IP_HEADER")
+        .ok()
+        .filter(|value| !value.trim().is_empty())?;
+    HeaderName::from_str(value.trim())
+        .map_err(|_| {
+            warn!(
+                "Invalid CLIENT_IP_HEADER '{}', using the peer address",
+                value
+            )
+        })
+        .ok()
+}
+
+/// Reads a comma-separated list from the environment, logging and skipping
+/// entries that don't parse.
+fn get_env_list<T: FromStr>(name: &str, default: &str) -> Vec<T> {
+    std::env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .filter_map(|value| {
+            let parsed = value.parse().ok();
+            if parsed.is_none() {
+                warn!("Ignoring invalid {} entry '{}'", name, value);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Cross-origin access to the `/api` routes for the origins listed in
+/// `CORS_ALLOWED_ORIGINS`, `*` allowing any. Without origins the API stays
+/// same-origin and no CORS headers are sent.
+fn create_cors_layer(config: &Config) -> Option<CorsLayer> {
+    if config.cors_allowed_origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if config
+        .cors_allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
+    {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(config.cors_allowed_origins.clone())
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(config.cors_allowed_methods.clone())
+            .allow_headers(config.cors_allowed_headers.clone()),
+    )
+}
+
+/// Public address of this instance, used wherever an absolute link to a
+/// document is needed: share URLs, QR codes and external link detection.
+fn get_base_url() -> String {
+    std::env::var("BASE_URL")
+        .ok()
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| DEFAULT_BASE_URL.to_string())
+}
+
+fn get_qr_error_correction() -> EcLevel {
+    match std::env::var("QR_ERROR_CORRECTION")
+        .unwrap_or_default()
+        .to_ascii_uppercase()
+        .as_str()
+    {
+        "L" => EcLevel::L,
+        "Q" => EcLevel::Q,
+        "H" => EcLevel::H,
+        _ => EcLevel::M,
+    }
+}
+
+/// Syntax highlighting theme from `HIGHLIGHT_THEME`, checked against the
+/// themes bundled with syntect so a typo fails at startup.
+fn get_highlight_theme() -> std::result::Result<String, String> {
+    let theme = std::env::var("HIGHLIGHT_THEME")
+        .ok()
+        .filter(|theme| !theme.is_empty())
+        .unwrap_or_else(|| DEFAULT_HIGHLIGHT_THEME.to_string());
+    if THEME_SET.themes.contains_key(&theme) {
+        return Ok(theme);
+    }
+    let available: Vec<&str> = THEME_SET.themes.keys().map(String::as_str).collect();
+    Err(format!(
+        "Unknown HIGHLIGHT_THEME '{}', expected one of: {}",
+        theme,
+        available.join(", ")
+    ))
+}
+
+fn get_asset_url(name: &str, default: &str) -> String {
+    std::env::var(name)
+        .ok()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+fn get_assets_dir() -> Option<PathBuf> {
+    std::env::var("ASSETS_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Origin of an absolute or protocol-relative URL, as a CSP source.
+/// Local paths have no origin of their own.
+fn asset_origin(url: &str) -> Option<String> {
+    let (scheme, rest) = match url.split_once("//") {
+        Some((scheme, rest)) if scheme.is_empty() || scheme.ends_with(':') => (scheme, rest),
+        _ => return None,
+    };
+    let host = rest
+        .split(['/', '?', '#'])
+        .next()
+        .filter(|host| !host.is_empty())?;
+    Some(
+        format!("{}//{}", scheme, host)
+            .trim_start_matches("//")
+            .to_string(),
+    )
+}
+
+fn get_shard_count() -> usize {
+    std::env::var("DATABASE_SHARDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_DB_SHARDS)
+        .clamp(1, MAX_DB_SHARDS)
+}
+
+fn get_shard_db_path(db_path: &str, index: usize) -> String {
+    match db_path.rfind('.') {
+        Some(dot) if !db_path[dot..].contains('/') => {
+            format!("{}-{:x}{}", &db_path[..dot], index, &db_path[dot..])
+        }
+        _ => format!("{}-{:x}", db_path, index),
+    }
+}
+
+/// Accepts a bare file path such as `./data/db.sqlite` as well as a
+/// `sqlite:` URL, since sqlx only parses the latter.
+fn normalize_database_url(url: &str) -> String {
+    let url = url.trim();
+    if url.starts_with("sqlite:") {
+        url.to_string()
+    } else {
+        format!("sqlite:{}", url)
+    }
+}
+
+/// The file a `sqlite:` URL points at, or `None` for in-memory databases.
+fn database_file_path(db_path: &str) -> Option<&FilePath> {
+    let path = db_path.strip_prefix("sqlite:")?;
+    let path = path.strip_prefix("//").unwrap_or(path);
+    let path = path.split('?').next().unwrap_or_default();
+    (!path.is_empty() && path != ":memory:").then(|| FilePath::new(path))
+}
+
+fn shard_index(id: &str, shard_count: usize) -> usize {
+    id.chars()
+        .next()
+        .and_then(|c| c.to_digit(16))
+        .map_or(0, |digit| digit as usize % shard_count)
+}
+
+async fn setup_database(db_path: &str) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(db_path)
+        .map_err(|err| format!("Invalid DATABASE_URL '{}': {}", db_path, err))?;
+    // SQLite creates the database file but not the directory it lives in.
+    if let Some(dir) = database_file_path(db_path).and_then(FilePath::parent) {
+        if !dir.as_os_str().is_empty() {
+            tokio::fs::create_dir_all(dir).await.map_err(|err| {
+                format!(
+                    "Failed to create the database directory '{}': {}",
+                    dir.display(),
+                    err
+                )
+            })?;
+        }
+    }
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect_with(
+            options
+                .create_if_missing(true)
+                .journal_mode(SqliteJournalMode::Wal)
+                .busy_timeout(Duration::from_secs(30)),
+        )
+        .await?;
+
+    sqlx::migrate!("./migrations").run(&pool).await?;
+
+    Ok(pool)
+}
+
+fn get_server_addr() -> SocketAddr {
+    let port = std::env::var("PORT")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(DEFAULT_PORT);
+
+    SocketAddr::new(get_bind_ip(), port)
+}
+
+/// Interface to listen on, from `BIND_ADDR` or `HOST`.
+fn get_bind_ip() -> IpAddr {
+    let value = std::env::var("BIND_ADDR")
+        .or_else(|_| std::env::var("HOST"))
+        .ok();
+    parse_bind_ip(value.as_deref())
+}
+
+/// Parses a bind address, falling back to all interfaces when it is unset or
+/// unparseable.
+fn parse_bind_ip(value: Option<&str>) -> IpAddr {
+    let all_interfaces = IpAddr::from([0, 0, 0, 0]);
+    let Some(value) = value.filter(|value| !value.is_empty()) else {
+        return all_interfaces;
+    };
+    value.parse().unwrap_or_else(|_| {
+        warn!(
+            "Invalid bind address '{}', listening on {}",
+            value, all_interfaces
+        );
+        all_interfaces
+    })
+}
+
+async fn handle_main_request(
+    State(config): State<Arc<Config>>,
+    params: Option<Query<RenderParams>>,
+) -> impl IntoResponse {
+    let params = params.map(|p| p.0);
+    let content = match params {
+        Some(RenderParams {
+            content: Some(content),
+            ..
+        }) => content,
+        Some(RenderParams {
+            template: Some(name),
+            ..
+        }) => find_template(&name).unwrap_or_default().to_string(),
+        _ => String::new(),
+    };
+
+    let markup = create_markdown_editor_page(&content, &config, None).await;
+    Html(markup.into_string())
+}
+
+async fn handle_preview_request(
+    State(config): State<Arc<Config>>,
+    State(render_limiter): State<RenderLimiter>,
+    input: std::result::Result<Form<MarkdownInput>, FormRejection>,
+) -> impl IntoResponse {
+    let Form(input) = match input {
+        Ok(input) => input,
+        Err(rejection) => {
+            return (rejection.status(), handle_400(&rejection.body_text())).into_response()
+        }
+    };
+
+    if input.content.len() > config.max_content_bytes {
+        return create_content_too_large_response(config.max_content_bytes).into_response();
+    }
+
+    let Some(_permit) = render_limiter.acquire().await else {
+        return handle_render_busy().into_response();
+    };
+
+    let html_output = if exceeds_nesting_depth(&input.content, config.max_nesting_depth) {
+        create_nesting_error_markup(config.max_nesting_depth).into_string()
+    } else {
+        // A bug in one of the render passes shouldn't take the editor down
+        // with it; show what went wrong in place of the preview instead.
+        std::panic::catch_unwind(AssertUnwindSafe(|| {
+            convert_markdown_to_html(&input.content, &config)
+        }))
+        .unwrap_or_else(|panic| {
+            let message = panic_message(panic.as_ref());
+            error!("Failed to render preview: {}", message);
+            create_render_error_markup(&message).into_string()
+        })
+    };
+    let lint_warnings = lint_markdown(&input.content);
+
+    let preview_markup = html! {
+        div id="markdown-preview" _="on load call MathJax.typeset()" {
+            br;
+            input id="preview-content" type="hidden" name="content" value=(&input.content);
+            @if !lint_warnings.is_empty() {
+                details id="lint-warnings" open {
+                    summary { "Warnings" }
+                    ul {
+                        @for warning in &lint_warnings {
+                            li { (warning) }
+                        }
+                    }
+                }
+            }
+            @if looks_like_html_document(&input.content) {
+                blockquote id="html-document-hint" {
+                    p {
+                        "This looks like an HTML page. mdow renders markdown, not HTML pages, so most of it will be stripped."
+                    }
+                    button
+                        type="button"
+                        data-wrapped=(wrap_in_code_block(&input.content, "html"))
+                        _="on click
+                           set #preview-content.value to my @data-wrapped
+                           call #edit-button.click()"
+                        { "Wrap in a code block" }
+                }
+            }
+            (PreEscaped(html_output))
+        }
+    };
+
+    Html(preview_markup.into_string()).into_response()
+}
+
+async fn handle_edit_request(Form(input): Form<MarkdownInput>) -> impl IntoResponse {
+    Html(create_markdown_textarea(&input.content).into_string())
+}
+
+/// Loads a markdown file from a URL into the editor. The fetch is made from
+/// the server, so only public http(s) addresses are allowed, the response is
+/// bounded in time and size, and each client shares the rate limit of
+/// `/share`. The markdown source is not run through `clean`: it is escaped
+/// as textarea text here and sanitized like any other document once it is
+/// previewed or shared, while cleaning it as HTML would strip tags from code
+/// blocks and escape `<` and `&` in the source.
+async fn handle_import_request(
+    State(config): State<Arc<Config>>,
+    Form(input): Form<ImportInput>,
+) -> std::result::Result<impl IntoResponse, ImportError> {
+    let content = fetch_import(&input.url, config.max_content_bytes).await?;
+    Ok(Html(create_markdown_textarea(&content).into_string()))
+}
+
+async fn handle_share_request(
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<Arc<Config>>,
+    State(render_limiter): State<RenderLimiter>,
+    Query(params): Query<ShareParams>,
+    headers: HeaderMap,
+    input: std::result::Result<Form<MarkdownInput>, FormRejection>,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let Form(input) = match input {
+        Ok(input) => input,
+        Err(rejection) => {
+            return Ok((rejection.status(), handle_400(&rejection.body_text())).into_response())
+        }
+    };
+
+    if is_blank_content(&input.content) {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Html(html! { p { "There is nothing to share yet." } }.into_string()),
+        )
+            .into_response());
+    }
+
+    if input.content.len() > config.max_content_bytes {
+        return Ok(create_content_too_large_response(config.max_content_bytes).into_response());
+    }
+
+    if !has_accepted_terms(&config, input.terms_accepted.as_deref()) {
+        return Ok(create_terms_required_response().into_response());
+    }
+
+    if exceeds_nesting_depth(&input.content, config.max_nesting_depth) {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Html(create_nesting_error_markup(config.max_nesting_depth).into_string()),
+        )
+            .into_response());
+    }
+
+    let rendered_html = {
+        let Some(_permit) = render_limiter.acquire().await else {
+            return Ok(handle_render_busy().into_response());
+        };
+        render_markdown(&input.content, &config)
+    };
+    let doc = create_markdown_document(
+        storage.as_ref(),
+        &config,
+        &input.content,
+        &rendered_html,
+        ShareOptions {
+            expiry_days: input.expiry_days,
+            never_expires: input.never_expires.as_deref() == Some("true"),
+            source: get_document_source(input.source.as_deref(), &headers),
+            theme: input
+                .theme
+                .and_then(|theme| theme.parse().ok())
+                .unwrap_or_default(),
+            password: input.password,
+            title: input.title,
+            indexable: input.indexable.as_deref() == Some("true"),
+        },
+    )
+    .await?;
+    let Some(doc) = doc else {
+        return Ok((StatusCode::INTERNAL_SERVER_ERROR, handle_500()).into_response());
+    };
+
+    let mut response_headers = create_owner_headers(&doc);
+    let title = document_title(doc.title.as_deref(), &input.content);
+    if params.format.as_deref() == Some("text") || accepts_plain_text(&headers) {
+        response_headers.insert(CONTENT_TYPE, "text/plain; charset=utf-8".parse().unwrap());
+        let url = format!(
+            "{}/view/{}\n",
+            config.base_url,
+            document_path_segment(&doc.id, title.as_deref())
+        );
+        return Ok((StatusCode::CREATED, response_headers, url).into_response());
+    }
+
+    Ok((
+        response_headers,
+        create_htmx_redirect_response(&doc.id, title.as_deref()),
+    )
+        .into_response())
+}
+
+/// Shares a copy of an existing document under a new id, with a fresh expiry
+/// and the caller as its owner.
+async fn handle_fork_request(
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<Arc<Config>>,
+    State(render_limiter): State<RenderLimiter>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    input: Option<Form<ForkInput>>,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let terms_accepted = input.and_then(|Form(input)| input.terms_accepted);
+    if !has_accepted_terms(&config, terms_accepted.as_deref()) {
+        return Ok(create_terms_required_response().into_response());
+    }
+
+    let source = match storage.fetch_document(&id).await? {
+        Some(source) if can_read(&source, &headers) => source,
+        _ => return Ok((StatusCode::NOT_FOUND, handle_404()).into_response()),
+    };
+
+    let rendered_html = {
+        let Some(_permit) = render_limiter.acquire().await else {
+            return Ok(handle_render_busy().into_response());
+        };
+        render_markdown(&source.content, &config)
+    };
+    let doc = create_markdown_document(
+        storage.as_ref(),
+        &config,
+        &source.content,
+        &rendered_html,
+        ShareOptions {
+            expiry_days: None,
+            never_expires: false,
+            source: get_document_source(None, &headers),
+            theme: source.theme.parse().unwrap_or_default(),
+            password: None,
+            title: source.title.clone(),
+            indexable: source.indexable,
+        },
+    )
+    .await?;
+    let Some(doc) = doc else {
+        return Ok((StatusCode::INTERNAL_SERVER_ERROR, handle_500()).into_response());
+    };
+    info!(id = %doc.id, source = %id, "Document forked");
+
+    let title = document_title(doc.title.as_deref(), &source.content);
+    Ok((
+        create_owner_headers(&doc),
+        create_htmx_redirect_response(&doc.id, title.as_deref()),
+    )
+        .into_response())
+}
+
+async fn handle_bundle_editor_request(State(config): State<Arc<Config>>) -> impl IntoResponse {
+    Html(create_bundle_editor_page(&config).into_string())
+}
+
+/// Shares several documents at once. The form sends `name` and `content`
+/// fields in pairs, in the order the files appear on the page; files left
+/// empty are skipped.
+async fn handle_share_bundle_request(
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<Arc<Config>>,
+    input: std::result::Result<Form<Vec<(String, String)>>, FormRejection>,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let Form(fields) = match input {
+        Ok(input) => input,
+        Err(rejection) => {
+            return Ok((rejection.status(), handle_400(&rejection.body_text())).into_response())
+        }
+    };
+
+    let mut entries = Vec::new();
+    let mut name = None;
+    let mut terms_accepted = None;
+    for (key, value) in fields {
+        match key.as_str() {
+            "terms_accepted" => terms_accepted = Some(value),
+            "name" => name = normalize_bundle_name(&value),
+            "content" if !is_blank_content(&value) => entries.push(BundleEntry {
+                name: name
+                    .take()
+                    .unwrap_or_else(|| format!("Document {}", entries.len() + 1)),
+                content: value,
+            }),
+            "content" => name = None,
+            _ => {}
+        }
+    }
+
+    if !has_accepted_terms(&config, terms_accepted.as_deref()) {
+        return Ok(create_terms_required_response().into_response());
+    }
+
+    if entries.is_empty() {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Html(html! { p { "There is nothing to share yet." } }.into_string()),
+        )
+            .into_response());
+    }
+
+    if entries.len() > MAX_BUNDLE_DOCUMENTS {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Html(
+                html! { p { "A bundle can hold at most " (MAX_BUNDLE_DOCUMENTS) " documents." } }
+                    .into_string(),
+            ),
+        )
+            .into_response());
+    }
+
+    let total_bytes: usize = entries.iter().map(|entry| entry.content.len()).sum();
+    if total_bytes > config.max_content_bytes {
+        return Ok(create_content_too_large_response(config.max_content_bytes).into_response());
+    }
+
+    if entries
+        .iter()
+        .any(|entry| exceeds_nesting_depth(&entry.content, config.max_nesting_depth))
+    {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Html(create_nesting_error_markup(config.max_nesting_depth).into_string()),
+        )
+            .into_response());
+    }
+
+    let Some(id) = create_document_bundle(storage.as_ref(), &config, &entries).await? else {
+        return Ok((StatusCode::INTERNAL_SERVER_ERROR, handle_500()).into_response());
+    };
+    Ok(create_htmx_redirect_response(&id, None))
+}
+
+async fn handle_api_share_request(
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<Arc<Config>>,
+    State(render_limiter): State<RenderLimiter>,
+    headers: HeaderMap,
+    input: std::result::Result<Json<ApiShareInput>, JsonRejection>,
+) -> std::result::Result<impl IntoResponse, ApiError> {
+    let Json(input) = input.map_err(|rejection| {
+        let status = match rejection {
+            JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            _ => rejection.status(),
+        };
+        ApiError::new(status, rejection.body_text())
+    })?;
+
+    if is_blank_content(&input.content) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Content must not be empty",
+        ));
+    }
+
+    let terms_accepted = input.terms_accepted.unwrap_or(false).then_some("true");
+    if !has_accepted_terms(&config, terms_accepted) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "The terms of use must be accepted",
+        ));
+    }
+
+    if input.content.len() > config.max_content_bytes {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Content must be at most {} bytes", config.max_content_bytes),
+        ));
+    }
+
+    if exceeds_nesting_depth(&input.content, config.max_nesting_depth) {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "Content is nested more than {} levels deep",
+                config.max_nesting_depth
+            ),
+        ));
+    }
+
+    let theme = match input.theme.as_deref().map(str::parse::<Theme>) {
+        None => Theme::default(),
+        Some(Ok(theme)) => theme,
+        Some(Err(err)) => return Err(ApiError::new(StatusCode::BAD_REQUEST, err)),
+    };
+
+    let rendered_html = {
+        let Some(_permit) = render_limiter.acquire().await else {
+            return Ok(handle_api_render_busy());
+        };
+        render_markdown(&input.content, &config)
+    };
+    let doc = create_markdown_document(
+        storage.as_ref(),
+        &config,
+        &input.content,
+        &rendered_html,
+        ShareOptions {
+            expiry_days: input.expiry_days,
+            never_expires: input.never_expires.unwrap_or(false),
+            source: get_document_source(input.source.as_deref(), &headers),
+            theme,
+            password: input.password,
+            title: input.title,
+            indexable: input.indexable.unwrap_or(false),
+        },
+    )
+    .await?;
+    let Some(doc) = doc else {
+        return Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Could not allocate a document id",
+        ));
+    };
+
+    let title = document_title(doc.title.as_deref(), &input.content);
+    Ok((
+        StatusCode::CREATED,
+        Json(ShareResponse {
+            url: format!(
+                "{}/view/{}",
+                config.base_url,
+                document_path_segment(&doc.id, title.as_deref())
+            ),
+            id: doc.id,
+            expires_at: doc.expires_at,
+            delete_token: doc.delete_token,
+            edit_token: doc.edit_token,
+        }),
+    )
+        .into_response())
+}
+
+async fn handle_update_page_request(
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<Arc<Config>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let doc = storage.fetch_document(&id).await?;
+
+    Ok(match doc {
+        Some(doc) if is_editor(&doc, &headers) => {
+            let markup = create_markdown_editor_page(&doc.content, &config, Some(&doc)).await;
+            Html(markup.into_string()).into_response()
+        }
+        _ => (StatusCode::NOT_FOUND, handle_404()).into_response(),
+    })
+}
+
+async fn handle_update_request(
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<Arc<Config>>,
+    State(render_limiter): State<RenderLimiter>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(input): Form<UpdateInput>,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    if is_blank_content(&input.content) {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Html(html! { p { "There is nothing to save yet." } }.into_string()),
+        )
+            .into_response());
+    }
+
+    if input.content.len() > config.max_content_bytes {
+        return Ok(create_content_too_large_response(config.max_content_bytes).into_response());
+    }
+
+    if exceeds_nesting_depth(&input.content, config.max_nesting_depth) {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Html(create_nesting_error_markup(config.max_nesting_depth).into_string()),
+        )
+            .into_response());
+    }
+
+    let Some(version) = input.version else {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Html(html! { p { "Missing the version this edit is based on." } }.into_string()),
+        )
+            .into_response());
+    };
+
+    let token = input
+        .token
+        .or_else(|| get_cookie(&headers, &edit_cookie_name(&id)).map(str::to_string));
+    let Some(token) = token else {
+        return Ok((StatusCode::NOT_FOUND, handle_404()).into_response());
+    };
+
+    let rendered_html = {
+        let Some(_permit) = render_limiter.acquire().await else {
+            return Ok(handle_render_busy().into_response());
+        };
+        render_markdown(&input.content, &config)
+    };
+    if storage
+        .update_document(&id, &token, version, &input.content, &rendered_html)
+        .await?
+    {
+        info!(id = %id, version = version + 1, "Document updated");
+        // Keep the slug and search title the document was shared under when
+        // it has a title of its own, rather than deriving one from the new
+        // content.
+        let stored_title = storage.fetch_document(&id).await?.and_then(|doc| doc.title);
+        let title = document_title(stored_title.as_deref(), &input.content);
+        if config.search_enabled {
+            storage
+                .reindex_document(&id, title.as_deref(), &input.content)
+                .await?;
+        }
+        return Ok(create_htmx_redirect_response(&id, title.as_deref()).into_response());
+    }
+
+    Ok(match storage.fetch_document(&id).await? {
+        Some(doc) if doc.edit_token.as_deref() == Some(token.as_str()) => (
+            StatusCode::CONFLICT,
+            Html(create_edit_conflict_markup(&doc).into_string()),
+        )
+            .into_response(),
+        _ => (StatusCode::NOT_FOUND, handle_404()).into_response(),
+    })
+}
+
+async fn handle_delete_request(
+    State(storage): State<Arc<dyn Storage>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(input): Form<DeleteInput>,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let token = input
+        .token
+        .or_else(|| get_cookie(&headers, &delete_cookie_name(&id)).map(str::to_string));
+
+    let deleted = match token {
+        Some(token) => storage.delete_document(&id, &token).await?,
+        None => false,
+    };
+
+    if !deleted {
+        return Ok((StatusCode::NOT_FOUND, handle_404()).into_response());
+    }
+    info!(id = %id, "Document deleted");
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert("hx-redirect", "/".parse().unwrap());
+    response_headers.insert(
+        SET_COOKIE,
+        format!("{}=; Path=/; Max-Age=0", delete_cookie_name(&id))
+            .parse()
+            .unwrap(),
+    );
+    Ok((response_headers, "").into_response())
+}
+
+async fn handle_view_request(
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<Arc<Config>>,
+    State(render_limiter): State<RenderLimiter>,
+    State(render_cache): State<RenderCache>,
+    Path(segment): Path<String>,
+    headers: HeaderMap,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let id = document_id_from_segment(&segment).to_string();
+    let doc = storage.fetch_document(&id).await?;
+
+    Ok(match doc {
+        Some(doc) if !can_read(&doc, &headers) => {
+            Html(create_password_prompt_page(&doc.id, false).into_string()).into_response()
+        }
+        Some(mut doc) => {
+            let is_owner = is_owner(&doc, &headers);
+            let is_editor = is_editor(&doc, &headers);
+            // The author lands here right after sharing and when checking on
+            // their document; only visits from readers are counted, and none
+            // while maintenance mode keeps the database read-only.
+            info!(id = %id, "Document viewed");
+            METRICS.documents_viewed.inc();
+            if !is_owner && !is_editor && !config.maintenance_mode {
+                let storage = storage.clone();
+                let id = id.clone();
+                tokio::spawn(async move { storage.record_view(&id).await });
+                doc.views += 1;
+            }
+
+            let mut response_headers = HeaderMap::new();
+            let etag = create_view_etag(&doc, is_owner, is_editor, Utc::now());
+            response_headers.insert(ETAG, etag.parse().unwrap());
+            response_headers.insert(
+                CACHE_CONTROL,
+                create_view_cache_control(&doc, is_owner || is_editor)
+                    .parse()
+                    .unwrap(),
+            );
+            response_headers.insert(VARY, "Cookie".parse().unwrap());
+            response_headers.insert(
+                "x-document-created",
+                doc.created_at.to_rfc3339().parse().unwrap(),
+            );
+            if let Some(expires_at) = doc.expires_at {
+                response_headers.insert(
+                    "x-document-expires",
+                    expires_at.to_rfc3339().parse().unwrap(),
+                );
+            }
+            if matches_etag(&headers, &etag) {
+                return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
+            }
+
+            let html_output = match doc.rendered_html.take() {
+                Some(rendered_html) => Bytes::from(rendered_html),
+                // Only documents shared before their HTML was stored get here.
+                None => match render_cache.get(&doc.content) {
+                    Some(html_output) => html_output,
+                    None => {
+                        let Some(_permit) = render_limiter.acquire().await else {
+                            return Ok(handle_render_busy().into_response());
+                        };
+                        let html_output = Bytes::from(render_markdown(&doc.content, &config));
+                        render_cache.insert(&doc.content, html_output.clone());
+                        html_output
+                    }
+                },
+            };
+            let chunks =
+                create_markdown_viewer_page(&doc, html_output, &config, is_owner, is_editor);
+            (response_headers, create_streamed_html_response(chunks)).into_response()
+        }
+        None => match storage.fetch_bundle(&id).await? {
+            Some(bundle) => {
+                let Ok(entries) = serde_json::from_str::<Vec<BundleEntry>>(&bundle.documents)
+                else {
+                    error!(id = %id, "Stored bundle is not valid JSON");
+                    return Ok((StatusCode::INTERNAL_SERVER_ERROR, handle_500()).into_response());
+                };
+                let Some(_permit) = render_limiter.acquire().await else {
+                    return Ok(handle_render_busy().into_response());
+                };
+                info!(id = %bundle.id, "Bundle viewed");
+                METRICS.documents_viewed.inc();
+                let rendered: Vec<String> = entries
+                    .iter()
+                    .map(|entry| render_markdown(&entry.content, &config))
+                    .collect();
+                Html(create_bundle_viewer_page(&bundle, &entries, &rendered).into_string())
+                    .into_response()
+            }
+            None => (StatusCode::NOT_FOUND, handle_404()).into_response(),
+        },
+    })
+}
+
+async fn handle_unlock_request(
+    State(storage): State<Arc<dyn Storage>>,
+    Path(id): Path<String>,
+    Form(input): Form<UnlockInput>,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let doc = storage.fetch_document(&id).await?;
+
+    Ok(match doc {
+        Some(MarkdownDocument {
+            password_hash: Some(password_hash),
+            unlock_token: Some(unlock_token),
+            ..
+        }) if verify_password(&input.password, &password_hash) => {
+            let mut response_headers = HeaderMap::new();
+            response_headers.insert(
+                SET_COOKIE,
+                format!(
+                    "{}={}; Path=/; HttpOnly; SameSite=Strict",
+                    unlock_cookie_name(&id),
+                    unlock_token
+                )
+                .parse()
+                .unwrap(),
+            );
+            (response_headers, Redirect::to(&format!("/view/{}", id))).into_response()
+        }
+        Some(MarkdownDocument {
+            password_hash: Some(_),
+            ..
+        }) => (
+            StatusCode::UNAUTHORIZED,
+            Html(create_password_prompt_page(&id, true).into_string()),
+        )
+            .into_response(),
+        Some(_) => Redirect::to(&format!("/view/{}", id)).into_response(),
+        None => (StatusCode::NOT_FOUND, handle_404()).into_response(),
+    })
+}
+
+async fn handle_fragment_request(
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<Arc<Config>>,
+    State(render_limiter): State<RenderLimiter>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let doc = storage.fetch_document(&id).await?;
+
+    Ok(match doc {
+        Some(doc) if !can_read(&doc, &headers) => {
+            (StatusCode::UNAUTHORIZED, Html(String::new())).into_response()
+        }
+        Some(mut doc) => {
+            let mut response_headers = HeaderMap::new();
+            let etag = create_view_etag(&doc, false, false, Utc::now());
+            response_headers.insert(ETAG, etag.parse().unwrap());
+            response_headers.insert(
+                CACHE_CONTROL,
+                create_view_cache_control(&doc, false).parse().unwrap(),
+            );
+            if matches_etag(&headers, &etag) {
+                return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
+            }
+
+            let html_output = match doc.rendered_html.take() {
+                Some(rendered_html) => rendered_html,
+                None => {
+                    let Some(_permit) = render_limiter.acquire().await else {
+                        return Ok(handle_render_busy().into_response());
+                    };
+                    render_markdown(&doc.content, &config)
+                }
+            };
+            (response_headers, Html(html_output)).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, Html(String::new())).into_response(),
+    })
+}
+
+async fn handle_raw_request(
+    State(storage): State<Arc<dyn Storage>>,
+    Path(id): Path<String>,
+    request_headers: HeaderMap,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let doc = storage.fetch_document(&id).await?;
+
+    Ok(match doc {
+        Some(doc) if !can_read(&doc, &request_headers) => (
+            StatusCode::UNAUTHORIZED,
+            "This document is password protected\n",
+        )
+            .into_response(),
+        Some(doc) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, "text/plain; charset=utf-8".parse().unwrap());
+            headers.insert(CONTENT_DISPOSITION, "inline".parse().unwrap());
+            (headers, doc.content).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "Document not found\n").into_response(),
+    })
+}
+
+async fn handle_download_request(
+    State(storage): State<Arc<dyn Storage>>,
+    State(config): State<Arc<Config>>,
+    State(render_limiter): State<RenderLimiter>,
+    Path(id): Path<String>,
+    request_headers: HeaderMap,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let doc = storage.fetch_document(&id).await?;
+
+    Ok(match doc {
+        Some(doc) if !can_read(&doc, &request_headers) => (
+            StatusCode::UNAUTHORIZED,
+            Html(create_password_prompt_page(&doc.id, false).into_string()),
+        )
+            .into_response(),
+        Some(mut doc) => {
+            let html_output = match doc.rendered_html.take() {
+                Some(rendered_html) => rendered_html,
+                None => {
+                    let Some(_permit) = render_limiter.acquire().await else {
+                        return Ok(handle_render_busy().into_response());
+                    };
+                    render_markdown(&doc.content, &config)
+                }
+            };
+            let (front_matter, _) = parse_front_matter(&doc.content);
+            let page_title = doc
+                .title
+                .clone()
+                .or_else(|| front_matter.and_then(|front_matter| front_matter.title))
+                .or_else(|| extract_title_from_html(&html_output));
+            let file_name = page_title
+                .as_deref()
+                .and_then(url_slug)
+                .unwrap_or_else(|| doc.id.clone());
+
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, "text/html; charset=utf-8".parse().unwrap());
+            headers.insert(
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.html\"", file_name)
+                    .parse()
+                    .unwrap(),
+            );
+            let markup = create_standalone_page(page_title.as_deref(), &html_output);
+            (headers, markup.into_string()).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, handle_404()).into_response(),
+    })
+}
+
+async fn handle_api_view_request(
+    State(storage): State<Arc<dyn Storage>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> std::result::Result<impl IntoResponse, ApiError> {
+    match storage.fetch_document(&id).await? {
+        Some(doc) if !can_read(&doc, &headers) => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            format!(
+                "Document is password protected; send the password in the {} header",
+                DOCUMENT_PASSWORD_HEADER
+            ),
+        )),
+        Some(doc) => Ok(Json(DocumentResponse::from(doc))),
+        None => Err(ApiError::new(StatusCode::NOT_FOUND, "Document not found")),
+    }
+}
+
+async fn handle_card_request(
+    State(storage): State<Arc<dyn Storage>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let doc = storage.fetch_document(&id).await?;
+
+    Ok(match doc {
+        Some(doc) if !can_read(&doc, &headers) => {
+            (StatusCode::NOT_FOUND, handle_404()).into_response()
+        }
+        Some(doc) => {
+            let mut headers = axum::http::HeaderMap::new();
+            headers.insert("content-type", "image/svg+xml".parse().unwrap());
+            (headers, create_card_svg(&doc).into_string()).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, handle_404()).into_response(),
+    })
+}
+
+/// Serves self-hosted copies of the page assets from `ASSETS_DIR`.
+async fn handle_asset_request(
+    State(config): State<Arc<Config>>,
+    Path(path): Path<String>,
+) -> impl IntoResponse {
+    let Some(file_path) = config
+        .assets_dir
+        .as_deref()
+        .and_then(|dir| resolve_asset_path(dir, &path))
+    else {
+        return (StatusCode::NOT_FOUND, handle_404()).into_response();
+    };
+
+    match tokio::fs::read(&file_path).await {
+        Ok(bytes) => ([(CONTENT_TYPE, asset_content_type(&file_path))], bytes).into_response(),
+        Err(_) => (StatusCode::NOT_FOUND, handle_404()).into_response(),
+    }
+}
+
+/// Full-text search over every readable document on the instance, for
+/// personal deployments that turn on `SEARCH_ENABLED`.
+async fn handle_search_request(
+    State(storage): State<Arc<dyn Storage>>,
+    params: std::result::Result<Query<SearchParams>, QueryRejection>,
+) -> std::result::Result<impl IntoResponse, ApiError> {
+    let Query(params) = params
+        .map_err(|rejection| ApiError::new(StatusCode::BAD_REQUEST, rejection.body_text()))?;
+    let Some(query) = create_search_query(&params.q) else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Search terms must not be empty",
+        ));
+    };
+
+    Ok(Json(storage.search(&query).await?))
+}
+
+/// Admits requests to the operator routes under `/stats` only when they
+/// send `Authorization: Bearer <STATS_TOKEN>`.
+async fn require_stats_token<B>(
+    State(config): State<Arc<Config>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Compared in constant time, so the response time doesn't tell how much
+    // of a guessed token was right.
+    let authorized = match (token, config.stats_token.as_deref()) {
+        (Some(token), Some(expected)) => bool::from(token.as_bytes().ct_eq(expected.as_bytes())),
+        _ => false,
+    };
+    if authorized {
+        return next.run(request).await;
+    }
+
+    let mut response =
+        ApiError::new(StatusCode::UNAUTHORIZED, "A valid bearer token is required").into_response();
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+/// Aggregate document stats for operators, as a page or, with
+/// `?format=json`, as JSON.
+async fn handle_stats_request(
+    State(storage): State<Arc<dyn Storage>>,
+    Query(params): Query<StatsParams>,
+) -> std::result::Result<Response, ApiError> {
+    let stats = storage.fetch_stats().await?;
+
+    Ok(match params.format.as_deref() {
+        Some("json") => Json(stats).into_response(),
+        _ => Html(create_stats_page(&stats).into_string()).into_response(),
+    })
+}
+
+/// Keeps crawlers off the raw and machine-readable copies of documents.
+/// Viewer pages stay crawlable so that their `noindex` tag can be seen.
+async fn handle_robots_request() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+        "User-agent: *\n\
+         Disallow: /raw/\n\
+         Disallow: /download/\n\
+         Disallow: /fragment/\n\
+         Disallow: /api/\n\
+         Disallow: /update/\n\
+         Disallow: /stats\n",
+    )
+}
+
+async fn handle_metrics_request() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    match encoder.encode(&METRICS.registry.gather(), &mut body) {
+        Ok(()) => ([(CONTENT_TYPE, encoder.format_type().to_string())], body).into_response(),
+        Err(err) => {
+            error!("Failed to encode metrics: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn handle_health_request(State(storage): State<Arc<dyn Storage>>) -> impl IntoResponse {
+    match storage.check_health().await {
+        Ok(()) => (StatusCode::OK, "ok"),
+        Err(err) => {
+            error!("Health check failed: {}", err);
+            (StatusCode::SERVICE_UNAVAILABLE, "unhealthy")
+        }
+    }
+}
+
+/// The most recently shared documents for operators, with the integration
+/// or client each came from.
+async fn handle_recent_documents_request(
+    State(storage): State<Arc<dyn Storage>>,
+) -> std::result::Result<impl IntoResponse, AppError> {
+    let docs = storage
+        .fetch_recent_documents(RECENT_DOCUMENTS_LIMIT)
+        .await?;
+
+    Ok(Html(create_recent_documents_page(&docs).into_string()))
+}
+
+async fn reject_writes_during_maintenance<B>(
+    State(config): State<Arc<Config>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !config.maintenance_mode {
+        return next.run(request).await;
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, MAINTENANCE_RETRY_AFTER_SECS.into());
+    if is_api_request(&request) {
+        let error = ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Sharing is paused for maintenance",
+        );
+        return (headers, error).into_response();
+    }
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        headers,
+        handle_maintenance(),
+    )
+        .into_response()
+}
+
+/// Whether a request targets the JSON API, whose clients expect an
+/// `ApiError` body rather than an HTML page.
+fn is_api_request<B>(request: &Request<B>) -> bool {
+    request.uri().path().starts_with("/api/")
+}
+
+async fn count_not_found<B>(request: Request<B>, next: Next<B>) -> Response {
+    let response = next.run(request).await;
+    if response.status() == StatusCode::NOT_FOUND {
+        METRICS.not_found.inc();
+    }
+    response
+}
+
+async fn add_security_headers<B>(
+    State(config): State<Arc<Config>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut response = next.run(request).await;
+    for (header, value) in &config.security_headers {
+        response.headers_mut().insert(header, value.clone());
+    }
+    response
+}
+
+async fn limit_share_rate<B>(
+    State(share_rate_limiter): State<ShareRateLimiter>,
+    State(config): State<Arc<Config>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let client = client_ip(
+        request.headers(),
+        config.client_ip_header.as_ref(),
+        peer.ip(),
+    );
+    if share_rate_limiter.try_acquire(client) {
+        return next.run(request).await;
+    }
+
+    let retry_after_secs =
+        (share_rate_limiter.window.as_secs() / u64::from(share_rate_limiter.limit)).max(1);
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, retry_after_secs.into());
+    if is_api_request(&request) {
+        let error = ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many documents shared, try again later",
+        );
+        return (headers, error).into_response();
+    }
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        headers,
+        Html(
+            html! {
+                p { "You're sharing or importing documents too quickly. Please wait a moment and try again." }
+            }
+            .into_string(),
+        ),
+    )
+        .into_response()
+}
+
+/// Address of the client behind a request. Behind a proxy every request
+/// comes from the proxy, so the address is taken from `CLIENT_IP_HEADER`
+/// when it is configured and present; for a list such as `X-Forwarded-For`
+/// the last entry, the one the proxy added, is used. Otherwise this is the
+/// connecting peer.
+fn client_ip(headers: &HeaderMap, header: Option<&HeaderName>, peer: IpAddr) -> IpAddr {
+    header
+        .and_then(|header| headers.get(header))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|address| address.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+/// Sent when no render permit frees up in time. From the editor it is shown
+/// in the editor's error element like any other failed request.
+fn handle_render_busy() -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, RENDER_RETRY_AFTER_SECS.into());
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        headers,
+        Html(
+            html! {
+                blockquote id="render-busy" {
+                    p { "mdow is busy rendering other documents right now. Please try again in a moment." }
+                }
+            }
+            .into_string(),
+        ),
+    )
+}
+
+fn handle_api_render_busy() -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, RENDER_RETRY_AFTER_SECS.into());
+    let error = ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Busy rendering other documents, try again later",
+    );
+    (headers, error).into_response()
+}
+
+fn handle_maintenance() -> Html<String> {
+    Html(
+        html! {
+            (create_html_head(Some("Maintenance"), None, None, None, None, false, false));
+            body a="auto" {
+                main class="content" aria-label="Content" {
+                    div class="w" {
+                        h1 { "Maintenance in progress" }
+                        p { "Sharing is paused for a few minutes. Existing documents can still be viewed." }
+                        p { a href="/" { "Return to homepage" } }
+                    }
+                }
+            }
+            (create_page_footer());
+        }
+        .into_string(),
+    )
+}
+
+fn handle_500() -> Html<String> {
+    Html(
+        html! {
+            (create_html_head(Some("500"), None, None, None, None, false, false));
+            body a="auto" {
+                main class="content" aria-label="Content" {
+                    div class="w" {
+                        h1 { "500 - Something Went Wrong" }
+                        p { "mdow couldn't complete your request. Please try again." }
+                        p { a href="/" { "Return to homepage" } }
+                    }
+                }
+            }
+            (create_page_footer());
+        }
+        .into_string(),
+    )
+}
+
+/// Page for a request mdow couldn't make sense of, such as a form missing
+/// its `content` field, with the reason it was refused.
+fn handle_400(reason: &str) -> Html<String> {
+    Html(
+        html! {
+            (create_html_head(Some("400"), None, None, None, None, false, false));
+            body a="auto" {
+                main class="content" aria-label="Content" {
+                    div class="w" {
+                        h1 { "400 - Bad Request" }
+                        p { "mdow couldn't read the submitted form." }
+                        p { code { (reason) } }
+                        p { a href="/" { "Return to homepage" } }
+                    }
+                }
+            }
+            (create_page_footer());
+        }
+        .into_string(),
+    )
+}
+
+fn handle_404() -> Html<String> {
+    Html(
+        html! {
+            (create_html_head(Some("404"), None, None, None, None, false, false));
+            body a="auto" {
+                main class="content" aria-label="Content" {
+                    div class="w" {
+                        h1 { "404 - Page Not Found" }
+                        p { "The page you're looking for doesn't exist." }
+                        p { a href="/" { "Return to homepage" } }
+                    }
+                }
+            }
+            (create_page_footer());
+        }
+        .into_string(),
+    )
+}
+
+async fn fetch_import(raw_url: &str, max_bytes: usize) -> std::result::Result<String, ImportError> {
+    let url = reqwest::Url::parse(raw_url.trim()).map_err(|_| ImportError::InvalidUrl)?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ImportError::InvalidUrl);
+    }
+    let port = url.port_or_known_default().ok_or(ImportError::InvalidUrl)?;
+
+    // Resolve once and pin the client to the checked addresses, so the name
+    // can't be re-resolved to an internal address for the actual request.
+    let mut client = reqwest::Client::builder()
+        .no_proxy()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(IMPORT_TIMEOUT);
+    let addresses: Vec<SocketAddr> = match url.host() {
+        Some(Host::Domain(domain)) => {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((domain, port))
+                .await
+                .map_err(|_| ImportError::UnknownHost)?
+                .collect();
+            client = client.resolve_to_addrs(domain, &addresses);
+            addresses
+        }
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        None => return Err(ImportError::InvalidUrl),
+    };
+    if addresses.is_empty() || !addresses.iter().all(|address| is_public_ip(address.ip())) {
+        return Err(ImportError::ForbiddenAddress);
+    }
+
+    let mut response = client.build()?.get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(ImportError::Status(response.status().as_u16()));
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_bytes as u64)
+    {
+        return Err(ImportError::TooLarge(max_bytes));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > max_bytes {
+            return Err(ImportError::TooLarge(max_bytes));
+        }
+    }
+    String::from_utf8(body).map_err(|_| ImportError::NotText)
+}
+
+/// Whether an address is reachable on the public internet, as opposed to
+/// loopback, private, link-local and other reserved ranges.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if ip.is_loopback() || ip.is_unspecified() {
+                return false;
+            }
+            if let Some(embedded) = embedded_ipv4(ip) {
+                return is_public_ip(IpAddr::V4(embedded));
+            }
+            let segments = ip.segments();
+            !(ip.is_multicast()
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] & 0xffc0) == 0xfec0
+                || segments[..3] == [0x64, 0xff9b, 0x1]
+                || segments[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
+/// The IPv4 address a translated or tunnelled IPv6 address leads to:
+/// IPv4-mapped `::ffff:a.b.c.d`, the deprecated IPv4-compatible `::a.b.c.d`,
+/// NAT64 `64:ff9b::a.b.c.d` and 6to4 `2002:aabb:ccdd::`.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let (high, low) = match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, high, low]
+        | [0, 0, 0, 0, 0, 0, high, low]
+        | [0x64, 0xff9b, 0, 0, 0, 0, high, low]
+        | [0x2002, high, low, ..] => (high, low),
+        _ => return None,
+    };
+    Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+}
+
+async fn save_markdown_document(
+    pool: &SqlitePool,
+    doc: &NewDocument,
+    content: &str,
+    rendered_html: &str,
+    source: Option<&str>,
+) -> std::result::Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO markdown_documents
+            (id, content, rendered_html, created_at, expires_at, source, delete_token,
+             edit_token, theme, password_hash, unlock_token, title, indexable)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&doc.id)
+    .bind(content)
+    .bind(rendered_html)
+    .bind(doc.created_at)
+    .bind(doc.expires_at)
+    .bind(source)
+    .bind(&doc.delete_token)
+    .bind(&doc.edit_token)
+    .bind(doc.theme.as_str())
+    .bind(&doc.password_hash)
+    .bind(&doc.unlock_token)
+    .bind(&doc.title)
+    .bind(doc.indexable)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn create_markdown_document(
+    storage: &dyn Storage,
+    config: &Config,
+    content: &str,
+    rendered_html: &str,
+    options: ShareOptions,
+) -> std::result::Result<Option<NewDocument>, AppError> {
+    let Some(document_id) = generate_unique_document_id(storage, config.id_length).await? else {
+        return Ok(None);
+    };
+    let creation_time = Utc::now();
+    let requested_expiry_days = if options.never_expires {
+        None
+    } else {
+        Some(
+            options
+                .expiry_days
+                .unwrap_or(config.default_expiry_days)
+                .clamp(MIN_EXPIRY_DAYS, MAX_REQUESTED_EXPIRY_DAYS),
+        )
+    };
+    let expiration_time = clamp_expiry_days(requested_expiry_days, config.max_expiry_days)
+        .map(|days| creation_time + chrono::Duration::days(days));
+
+    let password_hash = match options.password.as_deref().filter(|p| !p.is_empty()) {
+        Some(password) => Some(hash_password(password)?),
+        None => None,
+    };
+
+    let doc = NewDocument {
+        id: document_id,
+        delete_token: generate_token(),
+        edit_token: generate_token(),
+        created_at: creation_time,
+        expires_at: expiration_time,
+        theme: options.theme,
+        unlock_token: password_hash.as_ref().map(|_| generate_token()),
+        password_hash,
+        title: normalize_title(options.title.as_deref()),
+        indexable: options.indexable,
+    };
+
+    storage
+        .create_document(&doc, content, rendered_html, options.source.as_deref())
+        .await?;
+    if config.search_enabled && doc.password_hash.is_none() {
+        let title = document_title(doc.title.as_deref(), content);
+        storage
+            .index_document(&doc.id, title.as_deref(), content)
+            .await?;
+    }
+    info!(id = %doc.id, "Document shared");
+    METRICS.documents_created.inc();
+
+    Ok(Some(doc))
+}
+
+/// Stores a bundle under a fresh id, returning the id, or `None` when no
+/// free id could be found. Bundles live for the default expiry.
+async fn create_document_bundle(
+    storage: &dyn Storage,
+    config: &Config,
+    entries: &[BundleEntry],
+) -> std::result::Result<Option<String>, sqlx::Error> {
+    let Some(id) = generate_unique_document_id(storage, config.id_length).await? else {
+        return Ok(None);
+    };
+    let created_at = Utc::now();
+    let expires_at = clamp_expiry_days(Some(config.default_expiry_days), config.max_expiry_days)
+        .map(|days| created_at + chrono::Duration::days(days));
+    let bundle = DocumentBundle {
+        id,
+        documents: serde_json::to_string(entries).expect("bundle entries serialize to JSON"),
+        created_at,
+        expires_at,
+    };
+
+    storage.create_bundle(&bundle).await?;
+    info!(id = %bundle.id, documents = entries.len(), "Bundle shared");
+    METRICS.documents_created.inc();
+
+    Ok(Some(bundle.id))
+}
+
+async fn save_document_bundle(
+    pool: &SqlitePool,
+    bundle: &DocumentBundle,
+) -> std::result::Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO document_bundles (id, documents, created_at, expires_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(&bundle.id)
+    .bind(&bundle.documents)
+    .bind(bundle.created_at)
+    .bind(bundle.expires_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn fetch_document_bundle(
+    pool: &SqlitePool,
+    id: &str,
+) -> std::result::Result<Option<DocumentBundle>, sqlx::Error> {
+    sqlx::query_as::<_, DocumentBundle>(
+        "SELECT * FROM document_bundles WHERE id = ? AND (expires_at IS NULL OR expires_at > datetime('now'))",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+fn normalize_bundle_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.chars().take(MAX_BUNDLE_NAME_CHARS).collect())
+}
+
+/// Picks an id of `id_length` characters that isn't taken yet. Only when
+/// several candidates in a row collide are ids widened, one character at a
+/// time.
+async fn generate_unique_document_id(
+    storage: &dyn Storage,
+    id_length: usize,
+) -> std::result::Result<Option<String>, sqlx::Error> {
+    for length in id_length..=(id_length + MAX_ID_WIDENING).min(MAX_ID_LENGTH) {
+        for _ in 0..ID_ATTEMPTS_PER_LENGTH {
+            let candidate = generate_short_uuid(length);
+            if !storage.id_exists(&candidate).await? {
+                return Ok(Some(candidate));
+            }
+        }
+    }
+    Ok(None)
+}
+
+async fn document_id_exists(pool: &SqlitePool, id: &str) -> std::result::Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM markdown_documents WHERE id = ?) \
+         OR EXISTS(SELECT 1 FROM document_bundles WHERE id = ?)",
+    )
+    .bind(id)
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Title a document is known by: the one given at share time, else the
+/// front matter title, else its first level-one heading.
+fn document_title(title: Option<&str>, content: &str) -> Option<String> {
+    title.map(str::to_string).or_else(|| {
+        let (front_matter, body) = parse_front_matter(content);
+        front_matter
+            .and_then(|front_matter| front_matter.title)
+            .or_else(|| extract_title_text(body))
+    })
+}
+
+fn normalize_title(title: Option<&str>) -> Option<String> {
+    title
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .map(|title| title.chars().take(MAX_TITLE_CHARS).collect())
+}
+
+fn get_document_source(explicit: Option<&str>, headers: &HeaderMap) -> Option<String> {
+    explicit
+        .or_else(|| {
+            headers
+                .get(USER_AGENT)
+                .and_then(|agent| agent.to_str().ok())
+        })
+        .map(|source| source.chars().take(MAX_SOURCE_CHARS).collect())
+}
+
+async fn index_document(
+    pool: &SqlitePool,
+    id: &str,
+    title: Option<&str>,
+    content: &str,
+) -> std::result::Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO markdown_documents_fts (id, title, content) VALUES (?, ?, ?)")
+        .bind(id)
+        .bind(title)
+        .bind(content)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+async fn reindex_document(
+    pool: &SqlitePool,
+    id: &str,
+    title: Option<&str>,
+    content: &str,
+) -> std::result::Result<(), sqlx::Error> {
+    sqlx::query("UPDATE markdown_documents_fts SET title = ?, content = ? WHERE id = ?")
+        .bind(title)
+        .bind(content)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+async fn fetch_document_stats(
+    pool: &SqlitePool,
+) -> std::result::Result<DocumentStats, sqlx::Error> {
+    sqlx::query_as::<_, DocumentStats>(
+        r#"
+        SELECT
+            COUNT(*) AS documents,
+            COALESCE(SUM(expires_at <= datetime('now', '+7 days')), 0) AS expiring_within_week,
+            COALESCE(SUM(LENGTH(CAST(content AS BLOB))
+                + COALESCE(LENGTH(CAST(rendered_html AS BLOB)), 0)), 0) AS stored_bytes,
+            MIN(created_at) AS oldest_created_at
+        FROM markdown_documents
+        WHERE expires_at IS NULL OR expires_at > datetime('now')
+        "#,
+    )
+    .fetch_one(pool)
+    .await
+}
+
+async fn fetch_recent_markdown_documents(
+    pool: &SqlitePool,
+    limit: usize,
+) -> std::result::Result<Vec<MarkdownDocument>, sqlx::Error> {
+    sqlx::query_as::<_, MarkdownDocument>(
+        "SELECT * FROM markdown_documents ORDER BY created_at DESC LIMIT ?",
+    )
+    .bind(limit as i64)
+    .fetch_all(pool)
+    .await
+}
+
+async fn search_documents(
+    pool: &SqlitePool,
+    query: &str,
+) -> std::result::Result<Vec<SearchResult>, sqlx::Error> {
+    sqlx::query_as::<_, SearchResult>(
+        r#"
+        SELECT fts.id, fts.title,
+            snippet(markdown_documents_fts, 2, '', '', '…', 16) AS snippet,
+            rank
+        FROM markdown_documents_fts AS fts
+        JOIN markdown_documents AS doc ON doc.id = fts.id
+        WHERE markdown_documents_fts MATCH ?
+            AND (doc.expires_at IS NULL OR doc.expires_at > datetime('now'))
+        ORDER BY rank
+        LIMIT ?
+        "#,
+    )
+    .bind(query)
+    .bind(SEARCH_RESULT_LIMIT as i64)
+    .fetch_all(pool)
+    .await
+}
+
+/// Turns free text into an FTS5 query matching every word, quoting each one
+/// so operators and punctuation in the input are taken literally.
+fn create_search_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+async fn purge_expired_documents_periodically(
+    storage: Arc<dyn Storage>,
+    config: Arc<Config>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if config.maintenance_mode {
+            info!("Skipping the expired document purge during maintenance");
+            continue;
+        }
+        let purged = storage.purge_expired().await;
+        info!("Purged {} expired documents", purged);
+    }
+}
+
+async fn purge_expired_documents(pool: &SqlitePool) -> std::result::Result<u64, sqlx::Error> {
+    let documents = sqlx::query(
+        "DELETE FROM markdown_documents WHERE expires_at IS NOT NULL AND expires_at <= datetime('now')",
+    )
+    .execute(pool)
+    .await?;
+    let bundles = sqlx::query(
+        "DELETE FROM document_bundles WHERE expires_at IS NOT NULL AND expires_at <= datetime('now')",
+    )
+    .execute(pool)
+    .await?;
+    Ok(documents.rows_affected() + bundles.rows_affected())
+}
+
+async fn record_document_view(pool: &SqlitePool, id: &str) {
+    let result = sqlx::query("UPDATE markdown_documents SET views = views + 1 WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await;
+    if let Err(err) = result {
+        warn!("Failed to record a view of {}: {}", id, err);
+    }
+}
+
+async fn update_markdown_document(
+    pool: &SqlitePool,
+    id: &str,
+    edit_token: &str,
+    version: i64,
+    content: &str,
+    rendered_html: &str,
+) -> std::result::Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE markdown_documents
+        SET content = ?, rendered_html = ?, version = version + 1
+        WHERE id = ? AND edit_token = ? AND version = ?
+            AND (expires_at IS NULL OR expires_at > datetime('now'))
+        "#,
+    )
+    .bind(content)
+    .bind(rendered_html)
+    .bind(id)
+    .bind(edit_token)
+    .bind(version)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+async fn delete_markdown_document(
+    pool: &SqlitePool,
+    id: &str,
+    delete_token: &str,
+) -> std::result::Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM markdown_documents WHERE id = ? AND delete_token = ?")
+        .bind(id)
+        .bind(delete_token)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+async fn fetch_markdown_document(
+    pool: &SqlitePool,
+    id: &str,
+) -> std::result::Result<Option<MarkdownDocument>, sqlx::Error> {
+    sqlx::query_as::<_, MarkdownDocument>(
+        "SELECT * FROM markdown_documents WHERE id = ? AND (expires_at IS NULL OR expires_at > datetime('now'))",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+fn convert_markdown_to_html(markdown_content: &str, config: &Config) -> String {
+    let _timer = METRICS.render_duration.start_timer();
+    let markdown_options = config.markdown_options;
+    let (_, markdown_content) = parse_front_matter(markdown_content);
+    let (markdown_content, abbreviations) = extract_abbreviations(markdown_content);
+    let mut events: Vec<Event> = Parser::new_ext(&markdown_content, markdown_options).collect();
+    events = render_callouts(events);
+    events = render_definition_lists(events);
+
+    let site_host = url_host(&config.base_url).unwrap_or_default();
+    events = events
+        .into_iter()
+        .map(|event| open_external_links_in_new_tab(event, &site_host))
+        .collect();
+    if config.external_link_indicators {
+        events = events
+            .into_iter()
+            .flat_map(|event| mark_external_links(event, &site_host))
+            .collect();
+    }
+    events = expand_emoji_shortcodes(events);
+    let headings;
+    (events, headings) = add_heading_anchors(events);
+    // Abbreviations become raw HTML, so they are expanded only once heading
+    // slugs and table of contents entries have been taken from the text.
+    if !abbreviations.is_empty() {
+        events = expand_abbreviations(events, &abbreviations);
+    }
+    events = insert_table_of_contents(events, &headings);
+    events = arrange_footnotes(events);
+    events = mark_task_lists(events);
+
+    let mut html_output = String::with_capacity(markdown_content.len() * 3 / 2);
+    push_html(
+        &mut html_output,
+        highlight_code_blocks(events, &config.highlight_theme).into_iter(),
+    );
+
+    SANITIZER.clean(&html_output).to_string()
+}
+
+/// Accepts only the declarations syntect and pulldown-cmark generate, such as
+/// `color:#323232;` or `text-align: center`, so user-supplied styles can't
+/// position elements or load URLs.
+fn is_allowed_inline_style(style: &str) -> bool {
+    style
+        .split(';')
+        .map(str::trim)
+        .filter(|declaration| !declaration.is_empty())
+        .all(|declaration| {
+            let Some((property, value)) = declaration.split_once(':') else {
+                return false;
+            };
+            matches!(
+                property.trim(),
+                "color"
+                    | "background-color"
+                    | "font-weight"
+                    | "font-style"
+                    | "text-decoration"
+                    | "text-align"
+            ) && value
+                .trim()
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '#' || c == ' ' || c == '-')
+        })
+}
+
+/// Produces unique, deterministic URL fragments for the headings of a document.
+#[derive(Default)]
+struct SlugGenerator {
+    used: HashSet<String>,
+}
+
+impl SlugGenerator {
+    fn slug(&mut self, text: &str) -> String {
+        let base = slugify(text);
+        let mut slug = base.clone();
+        let mut suffix = 1;
+        while self.used.contains(&slug) {
+            slug = format!("{}-{}", base, suffix);
+            suffix += 1;
+        }
+        self.used.insert(slug.clone());
+        slug
+    }
+}
+
+fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() || c == '_' {
+            slug.push(c);
+        } else if (c.is_whitespace() || c == '-') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let slug = slug.trim_matches('-');
+    if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// A heading as it appears in a table of contents.
+struct HeadingEntry {
+    level: HeadingLevel,
+    slug: String,
+    text: String,
+}
+
+/// Gives every heading an `id` and appends a `#` link pointing at it,
+/// returning the headings found along the way.
+fn add_heading_anchors(events: Vec<Event>) -> (Vec<Event>, Vec<HeadingEntry>) {
+    let mut slugs = SlugGenerator::default();
+    let mut anchored = Vec::with_capacity(events.len());
+    let mut headings = Vec::new();
+    let mut heading: Option<Vec<Event>> = None;
+
+    for event in events {
+        match (event, heading.as_mut()) {
+            (event @ Event::Start(Tag::Heading(..)), None) => heading = Some(vec![event]),
+            (Event::End(Tag::Heading(level, id, classes)), Some(_)) => {
+                let inner = heading.take().unwrap().split_off(1);
+                let text: String = inner
+                    .iter()
+                    .filter_map(|event| match event {
+                        Event::Text(text) | Event::Code(text) => Some(text.as_ref()),
+                        _ => None,
+                    })
+                    .collect();
+                let slug = match id {
+                    Some(id) => {
+                        slugs.used.insert(id.to_string());
+                        id.to_string()
+                    }
+                    None => slugs.slug(&text),
+                };
+
+                let mut open_tag = format!("<{} id=\"", level);
+                escape_html(&mut open_tag, &slug).unwrap();
+                if !classes.is_empty() {
+                    open_tag.push_str("\" class=\"");
+                    escape_html(&mut open_tag, &classes.join(" ")).unwrap();
+                }
+                open_tag.push_str("\">");
+
+                let mut close_tag = String::from("<a class=\"heading-anchor\" href=\"#");
+                escape_html(&mut close_tag, &slug).unwrap();
+                close_tag.push_str(&format!("\">#</a></{}>\n", level));
+
+                anchored.push(Event::Html(open_tag.into()));
+                anchored.extend(inner);
+                anchored.push(Event::Html(close_tag.into()));
+                headings.push(HeadingEntry { level, slug, text });
+            }
+            (event, Some(inner)) => inner.push(event),
+            (event, None) => anchored.push(event),
+        }
+    }
+
+    (anchored, headings)
+}
+
+/// Replaces paragraphs consisting of just `[TOC]` or `[[toc]]` with a table
+/// of contents built from the document's headings.
+fn insert_table_of_contents<'a>(
+    events: Vec<Event<'a>>,
+    headings: &[HeadingEntry],
+) -> Vec<Event<'a>> {
+    let mut output = Vec::with_capacity(events.len());
+    let mut paragraph: Option<Vec<Event>> = None;
+
+    for event in events {
+        match (event, paragraph.as_mut()) {
+            (event @ Event::Start(Tag::Paragraph), None) => paragraph = Some(vec![event]),
+            (event @ Event::End(Tag::Paragraph), Some(_)) => {
+                let mut inner = paragraph.take().unwrap();
+                let marker = inner[1..]
+                    .iter()
+                    .all(|event| matches!(event, Event::Text(_)))
+                    && is_toc_marker(
+                        &inner[1..]
+                            .iter()
+                            .filter_map(|event| match event {
+                                Event::Text(text) => Some(text.as_ref()),
+                                _ => None,
+                            })
+                            .collect::<String>(),
+                    );
+                if marker {
+                    output.push(Event::Html(create_table_of_contents(headings).into()));
+                } else {
+                    inner.push(event);
+                    output.extend(inner);
+                }
+            }
+            (event, Some(inner)) => inner.push(event),
+            (event, None) => output.push(event),
+        }
+    }
+
+    output
+}
+
+fn is_toc_marker(text: &str) -> bool {
+    let text = text.trim();
+    text == "[TOC]" || text.eq_ignore_ascii_case("[[toc]]")
+}
+
+/// Renders headings as nested lists, opening a new level whenever a heading
+/// is deeper than the one before it.
+fn create_table_of_contents(headings: &[HeadingEntry]) -> String {
+    let mut toc = String::from("<nav class=\"table-of-contents\">\n");
+    let mut open_levels: Vec<HeadingLevel> = Vec::new();
+
+    for heading in headings {
+        while open_levels
+            .last()
+            .is_some_and(|level| *level > heading.level)
+        {
+            toc.push_str("</li>\n</ul>\n");
+            open_levels.pop();
+        }
+        if open_levels.last() == Some(&heading.level) {
+            toc.push_str("</li>\n");
+        } else {
+            toc.push_str("<ul>\n");
+            open_levels.push(heading.level);
+        }
+        toc.push_str("<li><a href=\"#");
+        escape_html(&mut toc, &heading.slug).unwrap();
+        toc.push_str("\">");
+        escape_html(&mut toc, &heading.text).unwrap();
+        toc.push_str("</a>");
+    }
+    for _ in open_levels {
+        toc.push_str("</li>\n</ul>\n");
+    }
+
+    toc.push_str("</nav>\n");
+    toc
+}
+
+/// Turns blockquotes that open with a GitHub-style `[!TYPE]` marker into
+/// callouts. Blockquotes with any other first line are left alone.
+fn render_callouts(events: Vec<Event>) -> Vec<Event> {
+    let mut rendered = Vec::with_capacity(events.len());
+    let mut blockquotes: Vec<bool> = Vec::new();
+    let mut index = 0;
+
+    while index < events.len() {
+        match &events[index] {
+            Event::Start(Tag::BlockQuote) => {
+                match match_callout_marker(&events[index + 1..]) {
+                    Some(((kind, label), marker_len)) => {
+                        rendered.push(Event::Html(
+                            format!(
+                                "<div class=\"callout callout-{}\"><p class=\"callout-title\">{}</p>\n",
+                                kind, label
+                            )
+                            .into(),
+                        ));
+                        blockquotes.push(true);
+                        index += 1;
+                        // Drop the marker, and its paragraph if nothing else is in it.
+                        if matches!(
+                            events.get(index + marker_len),
+                            Some(Event::End(Tag::Paragraph))
+                        ) {
+                            index += marker_len + 1;
+                        } else {
+                            rendered.push(events[index].clone());
+                            index += marker_len;
+                            if matches!(
+                                events.get(index),
+                                Some(Event::SoftBreak | Event::HardBreak)
+                            ) {
+                                index += 1;
+                            }
+                        }
+                        continue;
+                    }
+                    None => blockquotes.push(false),
+                }
+            }
+            Event::End(Tag::BlockQuote) if blockquotes.pop() == Some(true) => {
+                rendered.push(Event::Html("</div>\n".into()));
+                index += 1;
+                continue;
+            }
+            _ => {}
+        }
+        rendered.push(events[index].clone());
+        index += 1;
+    }
+
+    rendered
+}
+
+/// Recognizes `[!NOTE]` and friends at the start of a blockquote, given the
+/// events after its opening tag. Returns the callout type and the number of
+/// events the marker spans after the paragraph start.
+fn match_callout_marker(events: &[Event]) -> Option<((&'static str, &'static str), usize)> {
+    let Some(Event::Start(Tag::Paragraph)) = events.first() else {
+        return None;
+    };
+    let mut marker = String::new();
+    let mut marker_len = 0;
+    for event in &events[1..] {
+        match event {
+            Event::Text(text) => marker.push_str(text),
+            _ => break,
+        }
+        marker_len += 1;
+    }
+
+    let kind = marker.trim().strip_prefix("[!")?.strip_suffix(']')?;
+    let callout = CALLOUT_TYPES
+        .into_iter()
+        .find(|(callout, _)| callout.eq_ignore_ascii_case(kind))?;
+    Some((callout, marker_len + 1))
+}
+
+/// Turns paragraphs written as a term followed by `: definition` lines into
+/// definition lists. A term may have several definitions and a paragraph
+/// several terms; lists in consecutive paragraphs are merged into one.
+fn render_definition_lists(events: Vec<Event>) -> Vec<Event> {
+    let mut rendered = Vec::with_capacity(events.len());
+    let mut index = 0;
+
+    while index < events.len() {
+        if let Event::Start(Tag::Paragraph) = events[index] {
+            let end = events[index..]
+                .iter()
+                .position(|event| matches!(event, Event::End(Tag::Paragraph)))
+                .map(|offset| index + offset);
+            if let Some((end, lines)) =
+                end.and_then(|end| Some((end, split_definition_lines(&events[index + 1..end])?)))
+            {
+                if matches!(rendered.last(), Some(Event::Html(html)) if html.as_ref() == "</dl>\n")
+                {
+                    rendered.pop();
+                } else {
+                    rendered.push(Event::Html("<dl>\n".into()));
+                }
+                for (is_definition, line) in lines {
+                    let tag = if is_definition { "dd" } else { "dt" };
+                    rendered.push(Event::Html(format!("<{}>", tag).into()));
+                    rendered.extend(line);
+                    rendered.push(Event::Html(format!("</{}>\n", tag).into()));
+                }
+                rendered.push(Event::Html("</dl>\n".into()));
+                index = end + 1;
+                continue;
+            }
+        }
+        rendered.push(events[index].clone());
+        index += 1;
+    }
+
+    rendered
+}
+
+/// Splits the contents of a paragraph into terms and definitions, with the
+/// `: ` marker removed. Returns `None` unless the paragraph opens with a term
+/// and ends with a definition.
+fn split_definition_lines<'a>(events: &[Event<'a>]) -> Option<Vec<(bool, Vec<Event<'a>>)>> {
+    let mut lines = vec![Vec::new()];
+    for event in events {
+        match event {
+            Event::SoftBreak | Event::HardBreak => lines.push(Vec::new()),
+            event => lines.last_mut().unwrap().push(event.clone()),
+        }
+    }
+
+    let mut split = Vec::with_capacity(lines.len());
+    for mut line in lines {
+        let definition = match line.first() {
+            Some(Event::Text(text)) => text
+                .strip_prefix(": ")
+                .map(|rest| rest.trim_start().to_string()),
+            _ => None,
+        };
+        match definition {
+            Some(rest) if rest.is_empty() => {
+                line.remove(0);
+                split.push((true, line));
+            }
+            Some(rest) => {
+                line[0] = Event::Text(rest.into());
+                split.push((true, line));
+            }
+            None => split.push((false, line)),
+        }
+    }
+
+    let opens_with_term = matches!(split.first(), Some((false, _)));
+    let ends_with_definition = matches!(split.last(), Some((true, _)));
+    (opens_with_term && ends_with_definition).then_some(split)
+}
+
+/// Adds GitHub's `contains-task-list` and `task-list-item` classes to lists
+/// with checkboxes, so they can be styled apart from ordinary lists.
+fn mark_task_lists(events: Vec<Event>) -> Vec<Event> {
+    let mut marked = Vec::with_capacity(events.len());
+    let mut lists: Vec<(usize, Option<u64>, bool)> = Vec::new();
+    let mut items: Vec<usize> = Vec::new();
+
+    for event in events {
+        match event {
+            Event::Start(Tag::List(start)) => {
+                lists.push((marked.len(), start, false));
+                marked.push(Event::Start(Tag::List(start)));
+            }
+            Event::Start(Tag::Item) => {
+                items.push(marked.len());
+                marked.push(Event::Start(Tag::Item));
+            }
+            Event::End(Tag::Item) => {
+                items.pop();
+                marked.push(Event::End(Tag::Item));
+            }
+            Event::TaskListMarker(checked) => {
+                if let (Some(&item), Some(list)) = (items.last(), lists.last_mut()) {
+                    marked[item] = Event::Html("<li class=\"task-list-item\">".into());
+                    list.2 = true;
+                }
+                marked.push(Event::TaskListMarker(checked));
+            }
+            Event::End(Tag::List(start)) => {
+                if let Some((index, _, true)) = lists.pop() {
+                    marked[index] = Event::Html(
+                        match start {
+                            None => "<ul class=\"contains-task-list\">\n".to_string(),
+                            Some(1) => "<ol class=\"contains-task-list\">\n".to_string(),
+                            Some(n) => {
+                                format!("<ol class=\"contains-task-list\" start=\"{}\">\n", n)
+                            }
+                        }
+                        .into(),
+                    );
+                }
+                marked.push(Event::End(Tag::List(start)));
+            }
+            event => marked.push(event),
+        }
+    }
+
+    marked
+}
+
+/// Replaces GitHub-style `:shortcode:` emoji in text. Code spans are separate
+/// events and code block contents are skipped, so both are left untouched.
+fn expand_emoji_shortcodes(events: Vec<Event>) -> Vec<Event> {
+    let mut expanded = Vec::with_capacity(events.len());
+    let mut in_code_block = false;
+    let mut pending_text = String::new();
+
+    for event in events {
+        match event {
+            // The parser can split a run of text around characters like `_`
+            // and `:`, so adjacent chunks are joined before matching.
+            Event::Text(text) if !in_code_block => pending_text.push_str(&text),
+            event => {
+                if !pending_text.is_empty() {
+                    let text = replace_emoji_shortcodes(&std::mem::take(&mut pending_text));
+                    expanded.push(Event::Text(text.into()));
+                }
+                match event {
+                    Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+                    Event::End(Tag::CodeBlock(_)) => in_code_block = false,
+                    _ => {}
+                }
+                expanded.push(event);
+            }
+        }
+    }
+    if !pending_text.is_empty() {
+        expanded.push(Event::Text(replace_emoji_shortcodes(&pending_text).into()));
+    }
+
+    expanded
+}
+
+fn replace_emoji_shortcodes(text: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        replaced.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after.find(':').and_then(|end| {
+            let name = &after[..end];
+            let is_shortcode = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-'));
+            is_shortcode
+                .then(|| emojis::get_by_shortcode(name))
+                .flatten()
+                .map(|emoji| (emoji, end))
+        });
+        match emoji {
+            Some((emoji, end)) => {
+                replaced.push_str(emoji.as_str());
+                rest = &after[end + 1..];
+            }
+            None => {
+                replaced.push(':');
+                rest = after;
+            }
+        }
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+/// Numbers footnote references in the order they first appear and moves the
+/// definitions into an ordered list at the end, each linking back to its
+/// first reference.
+fn arrange_footnotes(events: Vec<Event>) -> Vec<Event> {
+    let mut body = Vec::with_capacity(events.len());
+    let mut definitions: Vec<(String, Vec<Event>)> = Vec::new();
+    let mut definition: Option<(String, Vec<Event>)> = None;
+    let mut referenced: Vec<String> = Vec::new();
+
+    for event in events {
+        match event {
+            Event::Start(Tag::FootnoteDefinition(label)) => {
+                definition = Some((label.to_string(), Vec::new()));
+            }
+            Event::End(Tag::FootnoteDefinition(_)) => definitions.extend(definition.take()),
+            Event::FootnoteReference(label) => {
+                let (number, first) = match referenced.iter().position(|seen| *seen == *label) {
+                    Some(index) => (index + 1, false),
+                    None => {
+                        referenced.push(label.to_string());
+                        (referenced.len(), true)
+                    }
+                };
+                let id = if first {
+                    format!(" id=\"fnref-{}\"", number)
+                } else {
+                    String::new()
+                };
+                let reference = Event::Html(
+                    format!(
+                        "<sup class=\"footnote-reference\"{}><a href=\"#fn-{}\">{}</a></sup>",
+                        id, number, number
+                    )
+                    .into(),
+                );
+                match definition.as_mut() {
+                    Some((_, inner)) => inner.push(reference),
+                    None => body.push(reference),
+                }
+            }
+            event => match definition.as_mut() {
+                Some((_, inner)) => inner.push(event),
+                None => body.push(event),
+            },
+        }
+    }
+
+    if definitions.is_empty() {
+        return body;
+    }
+
+    definitions.sort_by_key(|(label, _)| {
+        referenced
+            .iter()
+            .position(|seen| seen == label)
+            .unwrap_or(usize::MAX)
+    });
+
+    body.push(Event::Html("<section class=\"footnotes\">\n<ol>\n".into()));
+    let mut unreferenced = 0;
+    for (label, mut inner) in definitions {
+        let position = referenced.iter().position(|seen| *seen == label);
+        let number = match position {
+            Some(index) => index + 1,
+            None => {
+                unreferenced += 1;
+                referenced.len() + unreferenced
+            }
+        };
+        body.push(Event::Html(
+            format!("<li id=\"fn-{}\" value=\"{}\">\n", number, number).into(),
+        ));
+        if position.is_some() {
+            let backref = Event::Html(
+                format!(
+                    " <a class=\"footnote-backref\" href=\"#fnref-{}\">↩</a>",
+                    number
+                )
+                .into(),
+            );
+            match inner.pop() {
+                Some(end @ Event::End(Tag::Paragraph)) => {
+                    inner.push(backref);
+                    inner.push(end);
+                }
+                last => {
+                    inner.extend(last);
+                    inner.push(backref);
+                }
+            }
+        }
+        body.extend(inner);
+        body.push(Event::Html("</li>\n".into()));
+    }
+    body.push(Event::Html("</ol>\n</section>\n".into()));
+
+    body
+}
+
+/// Removes Markdown Extra style `*[TERM]: Definition` lines from a document,
+/// returning the remaining markdown and the defined abbreviations.
+fn extract_abbreviations(markdown_content: &str) -> (String, Vec<(String, String)>) {
+    let mut remaining = String::with_capacity(markdown_content.len());
+    let mut abbreviations = Vec::new();
+    let mut in_fence = false;
+
+    for line in markdown_content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        let definition = (!in_fence)
+            .then(|| trimmed.strip_prefix("*["))
+            .flatten()
+            .and_then(|rest| rest.split_once("]:"))
+            .filter(|(term, title)| !term.trim().is_empty() && !title.trim().is_empty());
+
+        match definition {
+            Some((term, title)) => {
+                abbreviations.push((term.trim().to_string(), title.trim().to_string()))
+            }
+            None => remaining.push_str(line),
+        }
+    }
+
+    (remaining, abbreviations)
+}
+
+/// Wraps whole-word occurrences of each abbreviation outside of code in
+/// `<abbr title="...">`.
+fn expand_abbreviations<'a>(
+    events: Vec<Event<'a>>,
+    abbreviations: &[(String, String)],
+) -> Vec<Event<'a>> {
+    let mut expanded = Vec::with_capacity(events.len());
+    let mut in_code_block = false;
+    // Text inside an image is its alt attribute, which cannot hold markup.
+    let mut image_depth = 0usize;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => in_code_block = false,
+            Event::Start(Tag::Image(..)) => image_depth += 1,
+            Event::End(Tag::Image(..)) => image_depth -= 1,
+            Event::Text(ref text) if !in_code_block && image_depth == 0 => {
+                expanded.extend(wrap_abbreviations(text, abbreviations));
+                continue;
+            }
+            _ => {}
+        }
+        expanded.push(event);
+    }
+
+    expanded
+}
+
+fn wrap_abbreviations<'a>(text: &str, abbreviations: &[(String, String)]) -> Vec<Event<'a>> {
+    let mut events = Vec::new();
+    let mut plain_start = 0;
+    let mut position = 0;
+
+    while position < text.len() {
+        let is_word_start = text[..position]
+            .chars()
+            .next_back()
+            .is_none_or(|c| !c.is_alphanumeric());
+        let matched = abbreviations.iter().find(|(term, _)| {
+            is_word_start
+                && text[position..].starts_with(term.as_str())
+                && text[position + term.len()..]
+                    .chars()
+                    .next()
+                    .is_none_or(|c| !c.is_alphanumeric())
+        });
+
+        match matched {
+            Some((term, title)) => {
+                if plain_start < position {
+                    events.push(Event::Text(CowStr::from(
+                        text[plain_start..position].to_string(),
+                    )));
+                }
+                let mut markup = String::from("<abbr title=\"");
+                escape_html(&mut markup, title).unwrap();
+                markup.push_str("\">");
+                escape_html(&mut markup, term).unwrap();
+                markup.push_str("</abbr>");
+                events.push(Event::Html(markup.into()));
+                position += term.len();
+                plain_start = position;
+            }
+            None => {
+                position += text[position..].chars().next().map_or(1, char::len_utf8);
+            }
+        }
+    }
+
+    if plain_start < text.len() {
+        events.push(Event::Text(CowStr::from(text[plain_start..].to_string())));
+    }
+    events
+}
+
+/// Appends the external link indicator to links that leave the site. It is
+/// raw HTML rather than text so it stays out of heading slugs and the table
+/// of contents.
+fn mark_external_links<'a>(event: Event<'a>, site_host: &str) -> Vec<Event<'a>> {
+    match event {
+        Event::End(Tag::Link(_, ref destination, _))
+            if is_external_link(destination, site_host) =>
+        {
+            vec![Event::Html(EXTERNAL_LINK_INDICATOR.into()), event]
+        }
+        event => vec![event],
+    }
+}
+
+/// Gives links that leave the site `target="_blank"`, so a shared document
+/// can't navigate its reader's tab away. Relative links stay in the tab.
+fn open_external_links_in_new_tab<'a>(event: Event<'a>, site_host: &str) -> Event<'a> {
+    match event {
+        Event::Start(Tag::Link(_, ref destination, ref title))
+            if is_external_link(destination, site_host) =>
+        {
+            let mut open_tag = String::from("<a href=\"");
+            escape_href(&mut open_tag, destination).unwrap();
+            if !title.is_empty() {
+                open_tag.push_str("\" title=\"");
+                escape_html(&mut open_tag, title).unwrap();
+            }
+            open_tag.push_str("\" target=\"_blank\">");
+            Event::Html(open_tag.into())
+        }
+        event => event,
+    }
+}
+
+fn is_external_link(destination: &str, site_host: &str) -> bool {
+    url_host(destination).is_some_and(|host| host != site_host)
+}
+
+/// Lowercased host of an absolute or protocol-relative URL.
+fn url_host(url: &str) -> Option<String> {
+    let lowercase = url.to_ascii_lowercase();
+    let rest = ["https://", "http://", "//"]
+        .iter()
+        .find_map(|scheme| lowercase.strip_prefix(scheme))?;
+
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    Some(host.split(':').next().unwrap_or_default().to_string())
+}
+
+fn set_markdown_parser_options(preset: MarkdownPreset) -> Options {
+    let mut options = Options::empty();
+    match preset {
+        MarkdownPreset::Default => {
+            options.insert(Options::ENABLE_TABLES);
+            options.insert(Options::ENABLE_STRIKETHROUGH);
+            options.insert(Options::ENABLE_TASKLISTS);
+            options.insert(Options::ENABLE_FOOTNOTES);
+            options.insert(Options::ENABLE_SMART_PUNCTUATION);
+        }
+        MarkdownPreset::Full => {
+            options.insert(Options::ENABLE_TABLES);
+            options.insert(Options::ENABLE_STRIKETHROUGH);
+            options.insert(Options::ENABLE_TASKLISTS);
+            options.insert(Options::ENABLE_FOOTNOTES);
+            options.insert(Options::ENABLE_SMART_PUNCTUATION);
+            options.insert(Options::ENABLE_HEADING_ATTRIBUTES);
+        }
+        MarkdownPreset::Commonmark => {}
+    }
+    options
+}
+
+/// Replaces each code block with pre-rendered HTML, highlighted with syntect
+/// when its fence names a known language.
+fn highlight_code_blocks<'a>(events: Vec<Event<'a>>, highlight_theme: &str) -> Vec<Event<'a>> {
+    let mut highlighted = Vec::with_capacity(events.len());
+    let mut code_block: Option<(Option<String>, String)> = None;
+
+    for event in events {
+        match (event, code_block.as_mut()) {
+            (Event::Start(Tag::CodeBlock(kind)), None) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info
+                        .split(|c: char| c.is_whitespace() || c == ',' || c == '{')
+                        .next()
+                        .filter(|language| !language.is_empty())
+                        .map(str::to_string),
+                    CodeBlockKind::Indented => None,
+                };
+                code_block = Some((language, String::new()));
+            }
+            (Event::Text(text), Some((_, code))) => code.push_str(&text),
+            (Event::End(Tag::CodeBlock(_)), Some(_)) => {
+                let (language, code) = code_block.take().unwrap();
+                highlighted.push(Event::Html(
+                    render_code_block(language.as_deref(), &code, highlight_theme).into(),
+                ));
+            }
+            (event, _) => highlighted.push(event),
+        }
+    }
+
+    highlighted
+}
+
+fn render_code_block(language: Option<&str>, code: &str, highlight_theme: &str) -> String {
+    if language == Some("mermaid") {
+        let mut html_output = String::from(MERMAID_BLOCK_OPEN_TAG);
+        escape_html(&mut html_output, code).unwrap();
+        html_output.push_str("</pre>\n");
+        return html_output;
+    }
+
+    let syntax = language.and_then(|language| SYNTAX_SET.find_syntax_by_token(language));
+    let highlighted = syntax.and_then(|syntax| {
+        highlighted_html_for_string(
+            code,
+            &SYNTAX_SET,
+            syntax,
+            &THEME_SET.themes[highlight_theme],
+        )
+        .ok()
+    });
+
+    let mut html_output = String::from("<div class=\"highlighter-rouge\">");
+    if let Some(language) = language {
+        html_output.push_str("<span class=\"code-language\">");
+        escape_html(&mut html_output, language).unwrap();
+        html_output.push_str("</span>");
+    }
+    html_output.push_str("<button type=\"button\" class=\"copy-code\">Copy</button>");
+    html_output.push_str("<div class=\"code-scroll\">");
+    match highlighted {
+        Some(highlighted) => html_output.push_str(&highlighted),
+        None => {
+            html_output.push_str("<pre><code>");
+            escape_html(&mut html_output, code).unwrap();
+            html_output.push_str("</code></pre>");
+        }
+    }
+    html_output.push_str("</div></div>\n");
+    html_output
+}
+
+fn count_tasks(markdown_content: &str) -> (usize, usize) {
+    Parser::new_ext(markdown_content, Options::ENABLE_TASKLISTS).fold(
+        (0, 0),
+        |(done, total), event| match event {
+            Event::TaskListMarker(checked) => (done + usize::from(checked), total + 1),
+            _ => (done, total),
+        },
+    )
+}
+
+/// Counts the words in a document outside of code blocks. Chinese and
+/// Japanese text isn't separated by spaces, so each Han or kana character
+/// counts as a word of its own.
+fn count_words(markdown_content: &str) -> usize {
+    let text = extract_plain_text(markdown_content);
+    let mut words = 0;
+    let mut in_word = false;
+    for c in text.chars() {
+        if is_cjk_character(c) {
+            words += 1;
+            in_word = false;
+        } else if c.is_whitespace() {
+            in_word = false;
+        } else if c.is_alphanumeric() && !in_word {
+            words += 1;
+            in_word = true;
+        }
+    }
+    words
+}
+
+fn is_cjk_character(c: char) -> bool {
+    matches!(
+        c,
+        '\u{3040}'..='\u{30FF}'
+            | '\u{3400}'..='\u{4DBF}'
+            | '\u{4E00}'..='\u{9FFF}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '\u{20000}'..='\u{2A6DF}'
+    )
+}
+
+fn estimate_reading_minutes(words: usize) -> usize {
+    words.div_ceil(READING_WORDS_PER_MINUTE).max(1)
+}
+
+/// Collects advisory warnings about likely mistakes in a document, shown
+/// alongside the preview.
+fn lint_markdown(markdown_content: &str) -> Vec<String> {
+    lint_footnotes(markdown_content)
+}
+
+/// Checks whether blocks or inlines in a document nest deeper than `max_depth`,
+/// so pathological inputs can be rejected before rendering and sanitizing them.
+fn exceeds_nesting_depth(markdown_content: &str, max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    for event in Parser::new_ext(markdown_content, Options::all()) {
+        match event {
+            Event::Start(_) => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            Event::End(_) => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+fn lint_footnotes(markdown_content: &str) -> Vec<String> {
+    let mut referenced = BTreeSet::new();
+    let mut defined = BTreeSet::new();
+    for event in Parser::new_ext(markdown_content, Options::ENABLE_FOOTNOTES) {
+        match event {
+            Event::FootnoteReference(label) => {
+                referenced.insert(label.into_string());
+            }
+            Event::Start(Tag::FootnoteDefinition(label)) => {
+                defined.insert(label.into_string());
+            }
+            _ => {}
+        }
+    }
+
+    let missing = referenced
+        .difference(&defined)
+        .map(|label| format!("Footnote [^{}] is referenced but never defined.", label));
+    let unused = defined
+        .difference(&referenced)
+        .map(|label| format!("Footnote [^{}] is defined but never referenced.", label));
+    missing.chain(unused).collect()
+}
+
+/// Whether the content would render to nothing, either because it is only
+/// whitespace or because everything in it is removed by the sanitizer.
+fn is_blank_content(content: &str) -> bool {
+    content.trim().is_empty() || SANITIZER.clean(content).to_string().trim().is_empty()
+}
+
+fn looks_like_html_document(content: &str) -> bool {
+    let start = content.trim_start();
+    let prefix = start.get(..9).unwrap_or(start).to_ascii_lowercase();
+    prefix.starts_with("<!doctype") || prefix.starts_with("<html")
+}
+
+fn wrap_in_code_block(content: &str, language: &str) -> String {
+    let longest_backtick_run = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_backtick_run.max(2) + 1);
+    format!("{}{}\n{}\n{}", fence, language, content.trim_end(), fence)
+}
+
+fn extract_title_text(markdown_content: &str) -> Option<String> {
+    let mut title: Option<String> = None;
+    for event in Parser::new(markdown_content) {
+        match (event, title.as_mut()) {
+            (Event::Start(Tag::Heading(HeadingLevel::H1, ..)), None) => title = Some(String::new()),
+            (Event::Text(text) | Event::Code(text), Some(title)) => title.push_str(&text),
+            (Event::End(Tag::Heading(HeadingLevel::H1, ..)), Some(_)) => break,
+            _ => {}
+        }
+    }
+    title.filter(|title| !title.trim().is_empty())
+}
+
+/// The document as plain text: the words of every block separated by single
+/// spaces, without code blocks, raw HTML or formatting markers.
+fn extract_plain_text(markdown_content: &str) -> String {
+    collect_plain_text(markdown_content, false)
+}
+
+fn collect_plain_text(markdown_content: &str, skip_title: bool) -> String {
+    let mut text = String::new();
+    let mut skipping = false;
+    let options = set_markdown_parser_options(MarkdownPreset::Default);
+    for event in Parser::new_ext(markdown_content, options) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => skipping = true,
+            Event::End(Tag::CodeBlock(_)) => skipping = false,
+            Event::Start(Tag::Heading(HeadingLevel::H1, ..)) if skip_title => skipping = true,
+            Event::End(Tag::Heading(HeadingLevel::H1, ..)) if skip_title => skipping = false,
+            Event::Text(chunk) | Event::Code(chunk) if !skipping => text.push_str(&chunk),
+            Event::SoftBreak | Event::HardBreak | Event::End(_) => text.push(' '),
+            _ => {}
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Opening text of the document after its title, cut to `max_chars`.
+fn extract_snippet(markdown_content: &str, max_chars: usize) -> String {
+    let collapsed = collect_plain_text(markdown_content, true);
+    if collapsed.chars().count() <= max_chars {
+        return collapsed;
+    }
+    let truncated: String = collapsed.chars().take(max_chars).collect();
+    format!("{}…", truncated.trim_end())
+}
+
+fn wrap_text(text: &str, line_chars: usize, max_lines: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.chars().count() + word.chars().count() < line_chars => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        if let Some(last) = lines.last_mut() {
+            last.push('…');
+        }
+    }
+    lines
+}
+
+fn extract_title_from_html(html_content: &str) -> Option<String> {
+    let start = html_content.find("<h1")?;
+    let inner_start = start + html_content[start..].find('>')? + 1;
+    let inner_end = inner_start + html_content[inner_start..].find("</h1>")?;
+    let inner = &html_content[inner_start..inner_end];
+    let inner = inner
+        .find("<a class=\"heading-anchor\"")
+        .map_or(inner, |anchor| &inner[..anchor]);
+
+    let mut title = String::with_capacity(inner.len());
+    let mut in_tag = false;
+    for c in inner.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => title.push(c),
+            _ => {}
+        }
+    }
+    Some(title)
+}
+
+/// Builds the shared `<head>`. Pages for a single document pass an `excerpt`,
+/// which adds OpenGraph and Twitter card tags so links unfurl with a preview,
+/// and load Mermaid only when they contain diagrams. Pages showing shared
+/// content pass `noindex` unless their author allowed indexing. The viewer
+/// passes a `canonical_url` so every slug variant resolves to one address,
+/// and a `card_url` so unfurlers show the document's preview card.
+fn create_html_head(
+    page_title: Option<&str>,
+    keywords: Option<&str>,
+    excerpt: Option<&str>,
+    canonical_url: Option<&str>,
+    card_url: Option<&str>,
+    mermaid: bool,
+    noindex: bool,
+) -> Markup {
+    let assets = &*ASSET_URLS;
+    html! {
+        head {
+            title { (page_title.unwrap_or("mdow")) };
+
+            meta charset="utf-8";
+            meta name="viewport" content="width=device-width, initial-scale=1";
+
+            meta name="title" content="mdow 🌾 | markdown on web";
+            meta name="description" content="A meadow for your markdown on web. A lightweight, browser-based markdown editor and previewer that makes sharing markdown files as simple as sharing a link.";
+            meta name="keywords" content=(keywords.unwrap_or(DEFAULT_KEYWORDS));
+            @if noindex {
+                meta name="robots" content="noindex";
+            }
+            @if let Some(canonical_url) = canonical_url {
+                link rel="canonical" href=(canonical_url);
+            }
+
+            @if let Some(excerpt) = excerpt {
+                meta property="og:title" content=(page_title.unwrap_or("mdow"));
+                meta property="og:description" content=(excerpt);
+                meta property="og:type" content="article";
+                meta property="og:site_name" content="mdow";
+                @if let Some(canonical_url) = canonical_url {
+                    meta property="og:url" content=(canonical_url);
+                }
+                meta name="twitter:title" content=(page_title.unwrap_or("mdow"));
+                meta name="twitter:description" content=(excerpt);
+                @if let Some(card_url) = card_url {
+                    meta property="og:image" content=(card_url);
+                    meta name="twitter:card" content="summary_large_image";
+                    meta name="twitter:image" content=(card_url);
+                } @else {
+                    meta name="twitter:card" content="summary";
+                }
+            }
+
+            meta name="application-name" content="mdow";
+            meta name="mobile-web-app-capable" content="yes";
+            meta name="apple-mobile-web-app-capable" content="yes";
+            meta name="apple-mobile-web-app-title" content="mdow";
+            meta name="apple-mobile-web-app-status-bar-style" content="default";
+            meta name="theme-color" content="#ffffff" media="(prefers-color-scheme: light)";
+            meta name="theme-color" content="#000000" media="(prefers-color-scheme: dark)";
+
+            link rel="apple-touch-icon" href="data:image/svg+xml,<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 100 100'><text y='.9em' font-size='90'>🌾</text></svg>";
+
+            link rel="icon" href="data:image/svg+xml,<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 100 100'><text y='.9em' font-size='90'>🌾</text></svg>";
+            link rel="stylesheet" href=(assets.stylesheet);
+            style { (PreEscaped(VIEWER_STYLESHEET)) }
+
+            script src=(assets.mathjax) async="" {};
+            script src=(assets.htmx) {};
+            script src=(assets.hyperscript) {};
+            script type="text/hyperscript" { (PreEscaped(COPY_CODE_HYPERSCRIPT)) }
+            script type="text/hyperscript" { (PreEscaped(EDITOR_ERROR_HYPERSCRIPT)) }
+            @if mermaid {
+                script src=(assets.mermaid) {};
+            }
+
+            @if let Some(goatcounter) = &assets.goatcounter {
+                script data-goatcounter=(goatcounter) async src=(assets.goatcounter_script) {};
+            }
+        }
+    }
+}
+
+fn create_markdown_textarea(content: &str) -> Markup {
+    html! {
+        textarea id="markdown-input" name="content" placeholder="Enter your markdown..." style="width: 100%; height: calc(100vh - 275px); resize: none;" {
+            (content)
+        }
+    }
+}
+
+fn create_page_footer() -> Markup {
+    html! {
+        footer {
+            div class="w" {
+                p { a href="https://yree.io/mdow" { "mdow" } " 🌾 :: a " a href="https://yree.io" { "Yree" } " product ♥" }
+            }
+        }
+    }
+}
+
+async fn create_markdown_editor_page(
+    initial_content: &str,
+    config: &Config,
+    editing: Option<&MarkdownDocument>,
+) -> Markup {
+    let default_expiry_days = config
+        .max_expiry_days
+        .map_or(config.default_expiry_days, |cap| {
+            config.default_expiry_days.min(cap)
+        });
+    let mut expiry_choices: Vec<(i64, String)> = EXPIRY_CHOICES
+        .iter()
+        .filter(|(days, _)| config.max_expiry_days.is_none_or(|cap| *days <= cap))
+        .map(|(days, label)| (*days, label.to_string()))
+        .collect();
+    if !expiry_choices
+        .iter()
+        .any(|(days, _)| *days == default_expiry_days)
+    {
+        expiry_choices.push((default_expiry_days, format!("{} days", default_expiry_days)));
+        expiry_choices.sort_by_key(|(days, _)| *days);
+    }
+
+    html! {
+        (create_html_head(None, None, None, None, None, false, false));
+        body a="auto" {
+            main class="content" aria-label="Content" {
+                div class="w" {
+                    h1 { "mdow 🌾" }
+                    p { dfn {"A meadow for your " b {"markdown on web."} } }
+                    p { "Enter your markdown, preview it, and share it." }
+                    @if initial_content.is_empty() && editing.is_none() {
+                        p {
+                            "Or start from a template: "
+                            @for (i, (name, label, _)) in TEMPLATES.iter().enumerate() {
+                                @if i > 0 { " · " }
+                                a href=(format!("/?template={}", name)) { (label) }
+                            }
+                        }
+                        form
+                            hx-post="/import"
+                            hx-target="#markdown-input"
+                            hx-swap="outerHTML"
+                            hx-disabled-elt="find button"
+                            _="on htmx:beforeRequest put '' into #import-status
+                               on htmx:responseError put event.detail.xhr.responseText into #import-status"
+                        {
+                            p {
+                                "Or import from a URL: "
+                                input type="url" name="url" placeholder="https://example.com/README.md" required;
+                                " "
+                                button type="submit" { "Import" }
+                                " "
+                                small id="import-status" {}
+                            }
+                        }
+                        p { "Or " a href="/bundle" { "share several files together" } "." }
+                    }
+                    div class="grid" data-error-target="#editor-error" {
+                        button
+                            id="preview-button"
+                            hx-post="/preview"
+                            hx-trigger="click"
+                            hx-target="#markdown-input"
+                            hx-swap="outerHTML"
+                            hx-include="#markdown-input"
+                            hx-validate="true"
+                            hx-disabled-elt="this"
+                            _="on htmx:afterRequest[detail.successful]
+                               hide me
+                               show #edit-button"
+                               { "Preview" }
+                        button
+                            id="edit-button"
+                            hx-post="/edit"
+                            hx-trigger="click"
+                            hx-target="#markdown-preview"
+                            hx-swap="outerHTML"
+                            hx-include="#markdown-preview"
+                            style="display: none;"
+                            hx-disabled-elt="this"
+                            _="on htmx:afterRequest[detail.successful]
+                               hide me
+                               show #preview-button"
+                               { "Edit" }
+                        @if let Some(doc) = editing {
+                            button
+                                id="save-button"
+                                hx-post=(format!("/update/{}", doc.id))
+                                hx-trigger="click"
+                                hx-include="[name='content'], .share-option"
+                                hx-validate="true"
+                                hx-disabled-elt="this"
+                                { "Save" }
+                        } @else {
+                            button
+                                id="share-button"
+                                hx-post="/share"
+                                hx-trigger="click"
+                                hx-include="[name='content'], .share-option"
+                                hx-validate="true"
+                                hx-disabled-elt="this"
+                                { "Share" }
+                        }
+                    }
+                    div id="editor-error" role="alert" {}
+                    @if let Some(doc) = editing {
+                        (create_version_input(doc.version, false))
+                    } @else {
+                        p {
+                            label {
+                                "Expires after "
+                                select class="share-option" name="expiry_days" {
+                                    @for (days, label) in &expiry_choices {
+                                        option value=(days) selected[*days == default_expiry_days] { (label) }
+                                    }
+                                }
+                            }
+                        }
+                        p {
+                            label {
+                                "Title "
+                                input class="share-option" type="text" name="title" placeholder="optional" maxlength=(MAX_TITLE_CHARS);
+                            }
+                        }
+                        p {
+                            label {
+                                "Theme "
+                                select class="share-option" name="theme" {
+                                    @for theme in Theme::ALL {
+                                        option value=(theme.as_str()) selected[theme == Theme::default()] { (theme.label()) }
+                                    }
+                                }
+                            }
+                        }
+                        p {
+                            label {
+                                "Password "
+                                input class="share-option" type="password" name="password" placeholder="optional" autocomplete="new-password";
+                            }
+                        }
+                        @if config.allow_indexing {
+                            p {
+                                label {
+                                    input class="share-option" type="checkbox" name="indexable" value="true";
+                                    " Let search engines index this document"
+                                }
+                            }
+                        }
+                        @if config.max_expiry_days.is_none() {
+                            p {
+                                label {
+                                    input class="share-option" type="checkbox" name="never_expires" value="true";
+                                    " Never expires"
+                                }
+                            }
+                        }
+                        (create_terms_checkbox(config))
+                    }
+                    textarea
+                        id="markdown-input"
+                        name="content"
+                        placeholder=(if initial_content.is_empty() { "Enter your markdown..." } else { "" })
+                        style="width: 100%; height: calc(100vh - 275px); resize: none;"
+                        required="required"
+                        _=(if initial_content.is_empty() {
+                            "on load
+                                if sessionStorage.getItem('clonedContent') is not null
+                                    set my.value to sessionStorage.getItem('clonedContent')
+                                    call sessionStorage.removeItem('clonedContent')
+                                else
+                                    set my.value to (localStorage.getItem('markdownContent'))
+                                end
+                             on input
+                                wait 500ms then
+                                call localStorage.setItem('markdownContent', my.value)"
+                        } else {
+                            "on input
+                                wait 500ms then
+                                call localStorage.setItem('markdownContent', my.value)"
+                        })
+                        { (initial_content) }
+                }
+            }
+        }
+        (create_page_footer());
+    }
+}
+
+/// Renders a document's markdown. Content nested too deeply to render safely
+/// is shown as plain text instead; callers hold a render permit.
+fn render_markdown(markdown_content: &str, config: &Config) -> String {
+    if exceeds_nesting_depth(markdown_content, config.max_nesting_depth) {
+        html! { pre { code { (markdown_content) } } }.into_string()
+    } else {
+        convert_markdown_to_html(markdown_content, config)
+    }
+}
+
+fn create_markdown_viewer_page(
+    doc: &MarkdownDocument,
+    html_output: Bytes,
+    config: &Config,
+    is_owner: bool,
+    is_editor: bool,
+) -> Vec<Bytes> {
+    let html = std::str::from_utf8(&html_output).unwrap_or_default();
+    let (front_matter, body) = parse_front_matter(&doc.content);
+    let front_matter = front_matter.unwrap_or_default();
+    let page_title = doc
+        .title
+        .clone()
+        .or_else(|| front_matter.title.clone())
+        .or_else(|| extract_title_from_html(html));
+    let keywords = extract_front_matter_keywords(&doc.content).map(|keywords| keywords.join(", "));
+    let (tasks_done, tasks_total) = count_tasks(body);
+    let word_count = count_words(body);
+    let excerpt = extract_snippet(body, OG_EXCERPT_CHARS);
+    let has_diagrams = html.contains(MERMAID_BLOCK_OPEN_TAG);
+    let qr_svg = generate_qr_svg(&config.base_url, &doc.id, config.qr_error_correction)
+        .map_err(|err| warn!("Failed to generate QR code for {}: {}", doc.id, err))
+        .ok();
+    let theme: Theme = doc.theme.parse().unwrap_or_default();
+    let canonical_url = format!("{}/view/{}", config.base_url, doc.id);
+    // Unfurlers fetch the card without the unlock cookie, and it would show
+    // the protected document's title and excerpt anyway.
+    let card_url = doc
+        .password_hash
+        .is_none()
+        .then(|| format!("{}/card.svg", canonical_url));
+    let now = Utc::now();
+    let expires_in = format_expires_in(doc.expires_at, now);
+    let expires_soon = doc
+        .expires_at
+        .is_some_and(|expires_at| expires_at - now < chrono::Duration::hours(24));
+
+    let page = html! {
+        (create_html_head(
+            page_title.as_deref(),
+            keywords.as_deref(),
+            Some(&excerpt),
+            Some(&canonical_url),
+            card_url.as_deref(),
+            has_diagrams,
+            !(config.allow_indexing && doc.indexable),
+        ));
+        body a=(theme.as_str()) {
+            main class="content" aria-label="Content" {
+                div class="w" id="markdown-view" _=(if has_diagrams {
+                    "on load call MathJax.typeset() then call mermaid.run()"
+                } else {
+                    "on load call MathJax.typeset()"
+                }) {
+                    (PreEscaped(DOCUMENT_BODY_PLACEHOLDER))
+                }
+            }
+            footer {
+                div class="w grid" {
+                    @if let Some(qr_svg) = qr_svg {
+                        (PreEscaped(qr_svg))
+                    }
+                    div {
+                        @if front_matter.author.is_some() || front_matter.date.is_some() {
+                            p {
+                                @if let Some(author) = &front_matter.author {
+                                    "by " (author)
+                                }
+                                @if front_matter.author.is_some() && front_matter.date.is_some() {
+                                    " · "
+                                }
+                                @if let Some(date) = &front_matter.date {
+                                    (date)
+                                }
+                            }
+                        }
+                        p {
+                            "created on " (doc.created_at.format("%Y-%m-%d"))
+                            " · "
+                            @if expires_soon {
+                                span class="expires-soon" { (expires_in) }
+                            } @else {
+                                (expires_in)
+                            }
+                            " · " (word_count) @if word_count == 1 { " word" } @else { " words" }
+                            " · " (estimate_reading_minutes(word_count)) " min read"
+                            " · " (doc.views) @if doc.views == 1 { " view" } @else { " views" }
+                        }
+                        @if tasks_total > 0 {
+                            p { (tasks_done) "/" (tasks_total) " done" }
+                        }
+                        p {
+                            a href=(format!("/raw/{}", doc.id)) { "raw" }
+                            " · "
+                            a href=(format!("/download/{}", doc.id)) { "download" }
+                        }
+                        p {
+                            @if is_editor {
+                                a href=(format!("/update/{}", doc.id)) { "edit" }
+                                " in "
+                            } @else {
+                                "made in "
+                            }
+                            a href="/" { "mdow" }
+                            " 🌾 :: "
+                            button
+                                id="clone-button"
+                                _="on click
+                                   call sessionStorage.setItem('clonedContent', #raw-content.value)
+                                   go to url /"
+                                { "Clone" }
+                            " "
+                            button
+                                id="fork-button"
+                                hx-post=(format!("/fork/{}", doc.id))
+                                hx-disabled-elt="this"
+                                hx-confirm=[config.require_terms.then_some("Duplicating shares a new copy. Do you accept the terms of use?")]
+                                hx-vals=[config.require_terms.then_some(r#"{"terms_accepted": "true"}"#)]
+                                { "Duplicate" }
+                            @if is_owner {
+                                " "
+                                button
+                                    id="delete-button"
+                                    hx-post=(format!("/delete/{}", doc.id))
+                                    hx-confirm="Delete this document? This cannot be undone."
+                                    { "Delete" }
+                            }
+                        }
+                        textarea id="raw-content" hidden { (doc.content) }
+                    }
+                }
+            }
+        }
+    }
+    .into_string();
+    split_page_around_document(page, html_output)
+}
+
+fn create_bundle_entry_fields() -> Markup {
+    html! {
+        fieldset class="bundle-entry" {
+            p {
+                label {
+                    "Name "
+                    input type="text" name="name" placeholder="notes.md" maxlength=(MAX_BUNDLE_NAME_CHARS);
+                }
+            }
+            textarea name="content" placeholder="Enter your markdown..." style="width: 100%; height: 30vh; resize: vertical;" {}
+        }
+    }
+}
+
+fn create_bundle_editor_page(config: &Config) -> Markup {
+    html! {
+        (create_html_head(Some("Share a bundle"), None, None, None, None, false, false));
+        body a="auto" {
+            main class="content" aria-label="Content" {
+                div class="w" {
+                    h1 { "mdow 🌾" }
+                    p { "Share several markdown files under one link, shown as tabs." }
+                    form
+                        hx-post="/share-bundle"
+                        hx-disabled-elt="find button[type='submit']"
+                        data-error-target="#editor-error"
+                    {
+                        div id="bundle-entries" {
+                            (create_bundle_entry_fields())
+                            (create_bundle_entry_fields())
+                        }
+                        template id="bundle-entry-template" {
+                            (create_bundle_entry_fields())
+                        }
+                        (create_terms_checkbox(config))
+                        div class="grid" {
+                            button
+                                type="button"
+                                _="on click put #bundle-entry-template.innerHTML at end of #bundle-entries"
+                                { "Add file" }
+                            button type="submit" { "Share bundle" }
+                        }
+                    }
+                    div id="editor-error" role="alert" {}
+                    p { a href="/" { "Back to the editor" } }
+                }
+            }
+            (create_page_footer());
+        }
+    }
+}
+
+fn create_stats_page(stats: &DocumentStats) -> Markup {
+    html! {
+        (create_html_head(Some("Stats"), None, None, None, None, false, true));
+        body a="auto" {
+            main class="content" aria-label="Content" {
+                div class="w" {
+                    h1 { "Stats" }
+                    table {
+                        tbody {
+                            tr { th { "Documents" } td { (stats.documents) } }
+                            tr { th { "Expiring within 7 days" } td { (stats.expiring_within_week) } }
+                            tr {
+                                th { "Stored" }
+                                td { (format_bytes(usize::try_from(stats.stored_bytes).unwrap_or_default())) }
+                            }
+                            tr {
+                                th { "Oldest document" }
+                                td {
+                                    @match stats.oldest_created_at {
+                                        Some(created_at) => (created_at.format("%Y-%m-%d")),
+                                        None => "none",
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            (create_page_footer());
+        }
+    }
+}
+
+fn create_recent_documents_page(docs: &[MarkdownDocument]) -> Markup {
+    html! {
+        (create_html_head(Some("Recent documents"), None, None, None, None, false, true));
+        body a="auto" {
+            main class="content" aria-label="Content" {
+                div class="w" {
+                    h1 { "Recent documents" }
+                    table {
+                        thead {
+                            tr { th { "ID" } th { "Created" } th { "Expires" } th { "Source" } }
+                        }
+                        tbody {
+                            @for doc in docs {
+                                tr {
+                                    td { (doc.id) }
+                                    td { (doc.created_at.format("%Y-%m-%d")) }
+                                    td {
+                                        @match doc.expires_at {
+                                            Some(expires_at) => (expires_at.format("%Y-%m-%d")),
+                                            None => "never",
+                                        }
+                                    }
+                                    td { (doc.source.as_deref().unwrap_or("unknown")) }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            (create_page_footer());
+        }
+    }
+}
+
+/// Viewer for a bundle: one tab per document, with only the selected
+/// document shown.
+fn create_bundle_viewer_page(
+    bundle: &DocumentBundle,
+    entries: &[BundleEntry],
+    rendered: &[String],
+) -> Markup {
+    html! {
+        (create_html_head(entries.first().map(|entry| entry.name.as_str()), None, None, None, None, false, true));
+        body a="auto" {
+            main class="content" aria-label="Content" {
+                div class="w" id="markdown-view" _="on load call MathJax.typeset()" {
+                    div class="bundle-tabs" role="tablist" {
+                        @for (index, entry) in entries.iter().enumerate() {
+                            button
+                                type="button"
+                                role="tab"
+                                class=(if index == 0 { "bundle-tab active" } else { "bundle-tab" })
+                                _=(format!(
+                                    "on click
+                                       take .active from .bundle-tab
+                                       hide .bundle-panel
+                                       show #bundle-panel-{}",
+                                    index
+                                ))
+                                { (entry.name) }
+                        }
+                    }
+                    @for (index, html_output) in rendered.iter().enumerate() {
+                        section
+                            class="bundle-panel"
+                            id=(format!("bundle-panel-{}", index))
+                            role="tabpanel"
+                            style=[(index > 0).then_some("display: none;")]
+                        {
+                            (PreEscaped(html_output))
+                        }
+                    }
+                }
+            }
+            footer {
+                div class="w" {
+                    p {
+                        (entries.len()) " documents · created on " (bundle.created_at.format("%Y-%m-%d"))
+                        @if let Some(expires_at) = bundle.expires_at {
+                            " · expires on " (expires_at.format("%Y-%m-%d"))
+                        }
+                    }
+                    p { "made in " a href="/" { "mdow" } " 🌾" }
+                }
+            }
+        }
+    }
+}
+
+fn create_password_prompt_page(document_id: &str, wrong_password: bool) -> Markup {
+    html! {
+        (create_html_head(Some("Password required"), None, None, None, None, false, true));
+        body a="auto" {
+            main class="content" aria-label="Content" {
+                div class="w" {
+                    h1 { "Password required" }
+                    p { "This document is protected. Enter its password to read it." }
+                    @if wrong_password {
+                        blockquote id="wrong-password" { p { "That password is not correct." } }
+                    }
+                    form method="post" action=(format!("/view/{}/unlock", document_id)) {
+                        p {
+                            input type="password" name="password" required autofocus;
+                            " "
+                            button type="submit" { "Unlock" }
+                        }
+                    }
+                }
+            }
+            (create_page_footer());
+        }
+    }
+}
+
+/// Shown in the editor's error element. The version input is swapped out of
+/// band over the editor's own, so the next save is based on the version the
+/// author has just seen.
+fn create_edit_conflict_markup(doc: &MarkdownDocument) -> Markup {
+    html! {
+        blockquote id="edit-conflict" {
+            p {
+                "This document changed since you started editing. Its current version is below; "
+                "merge your changes into it and save again."
+            }
+            textarea readonly style="width: 100%; height: 40vh;" { (doc.content) }
+        }
+        (create_version_input(doc.version, true))
+    }
+}
+
+/// The version an edit is based on, sent along with every save.
+fn create_version_input(version: i64, swap_oob: bool) -> Markup {
+    html! {
+        input
+            id="edit-version"
+            class="share-option"
+            type="hidden"
+            name="version"
+            value=(version)
+            hx-swap-oob=[swap_oob.then_some("true")];
+    }
+}
+
+/// Applies the operator's expiry cap to a requested expiry, where `None` means
+/// the document should never expire.
+fn clamp_expiry_days(requested: Option<i64>, cap: Option<i64>) -> Option<i64> {
+    match (requested, cap) {
+        (Some(days), Some(cap)) => Some(days.min(cap)),
+        (None, Some(cap)) => Some(cap),
+        (requested, None) => requested,
+    }
+}
+
+fn create_standalone_page(page_title: Option<&str>, html_output: &str) -> Markup {
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { (page_title.unwrap_or("mdow")) }
+                style { (PreEscaped(STANDALONE_STYLESHEET)) }
+            }
+            body {
+                main {
+                    (PreEscaped(html_output))
+                }
+            }
+        }
+    }
+}
+
+/// Cuts a rendered page at the placeholder for the document, so the rendered
+/// document is sent as its own chunk instead of being copied into the page.
+fn split_page_around_document(mut page: String, html_output: Bytes) -> Vec<Bytes> {
+    let Some(start) = page.find(DOCUMENT_BODY_PLACEHOLDER) else {
+        return vec![page.into()];
+    };
+    let tail = page.split_off(start + DOCUMENT_BODY_PLACEHOLDER.len());
+    page.truncate(start);
+    vec![page.into(), html_output, tail.into()]
+}
+
+fn create_streamed_html_response(chunks: Vec<Bytes>) -> impl IntoResponse {
+    let content_length: usize = chunks.iter().map(Bytes::len).sum();
+    let body = StreamBody::new(stream::iter(chunks.into_iter().map(Ok::<_, Infallible>)));
+    (
+        [
+            (CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+            (CONTENT_LENGTH, content_length.to_string()),
+        ],
+        body,
+    )
+}
+
+fn create_card_svg(doc: &MarkdownDocument) -> Markup {
+    let (front_matter, body) = parse_front_matter(&doc.content);
+    let title = doc
+        .title
+        .clone()
+        .or_else(|| front_matter.and_then(|front_matter| front_matter.title))
+        .or_else(|| extract_title_text(body))
+        .unwrap_or_else(|| "mdow".to_string());
+    let title_lines = wrap_text(&title, 36, 2);
+    let snippet_lines = wrap_text(&extract_snippet(body, 200), 64, 3);
+
+    html! {
+        svg xmlns="http://www.w3.org/2000/svg" width="1200" height="630" viewBox="0 0 1200 630" {
+            rect width="1200" height="630" fill="#ffffff" {}
+            rect x="0" y="0" width="16" height="630" fill="#000000" {}
+            @for (line, y) in title_lines.iter().zip((140..).step_by(72)) {
+                text x="80" y=(y) font-family="sans-serif" font-size="60" font-weight="bold" fill="#000000" { (line) }
+            }
+            @for (line, y) in snippet_lines.iter().zip((320..).step_by(44)) {
+                text x="80" y=(y) font-family="sans-serif" font-size="30" fill="#444444" { (line) }
+            }
+            text x="80" y="560" font-family="sans-serif" font-size="28" fill="#000000" { "mdow 🌾" }
+            text x="1120" y="560" text-anchor="end" font-family="sans-serif" font-size="28" fill="#666666" {
+                "created on " (doc.created_at.format("%Y-%m-%d"))
+            }
+        }
+    }
+}
+
+/// Whether a share may go ahead: always, unless `REQUIRE_TERMS` is on and
+/// the terms weren't accepted.
+fn has_accepted_terms(config: &Config, terms_accepted: Option<&str>) -> bool {
+    !config.require_terms || terms_accepted == Some("true")
+}
+
+fn create_terms_required_response() -> impl IntoResponse {
+    (
+        StatusCode::BAD_REQUEST,
+        Html(html! { p { "Please accept the terms of use before sharing." } }.into_string()),
+    )
+}
+
+fn create_terms_checkbox(config: &Config) -> Markup {
+    html! {
+        @if config.require_terms {
+            p {
+                label {
+                    input class="share-option" type="checkbox" name="terms_accepted" value="true" required;
+                    " I accept the "
+                    @match &config.terms_url {
+                        Some(terms_url) => a href=(terms_url) target="_blank" { "terms of use" },
+                        None => "terms of use",
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn create_content_too_large_response(max_content_bytes: usize) -> impl IntoResponse {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Html(
+            html! {
+                blockquote id="content-too-large" {
+                    p {
+                        "This document is too large to share. Please keep it under "
+                        (format_bytes(max_content_bytes)) "."
+                    }
+                }
+            }
+            .into_string(),
+        ),
+    )
+}
+
+/// Describes how long a document has left, e.g. "expires in 12 days", in
+/// the largest whole unit that fits.
+fn format_expires_in(expires_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> String {
+    let Some(expires_at) = expires_at else {
+        return "never expires".to_string();
+    };
+    let remaining = expires_at - now;
+    let (amount, unit) = if remaining.num_days() >= 1 {
+        (remaining.num_days(), "day")
+    } else if remaining.num_hours() >= 1 {
+        (remaining.num_hours(), "hour")
+    } else if remaining.num_minutes() >= 1 {
+        (remaining.num_minutes(), "minute")
+    } else {
+        return "expires in less than a minute".to_string();
+    };
+    format!(
+        "expires in {} {}{}",
+        amount,
+        unit,
+        if amount == 1 { "" } else { "s" }
+    )
+}
+
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        bytes if bytes >= 1024 * 1024 => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+        bytes if bytes >= 1024 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        bytes => format!("{} bytes", bytes),
+    }
+}
+
+fn create_render_error_markup(message: &str) -> Markup {
+    html! {
+        blockquote id="render-error" {
+            p { "Something went wrong while rendering this preview. Your text is safe; keep editing or try again." }
+            details {
+                summary { "Error details" }
+                pre { code { (message) } }
+            }
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown error".to_string())
+}
+
+fn create_nesting_error_markup(max_depth: usize) -> Markup {
+    html! {
+        blockquote id="nesting-error" {
+            p {
+                "This document is nested too deeply to render. Please keep lists and quotes within "
+                (max_depth) " levels."
+            }
+        }
+    }
+}
+
+fn find_template(name: &str) -> Option<&'static str> {
+    TEMPLATES
+        .iter()
+        .find(|(template_name, _, _)| *template_name == name)
+        .map(|(_, _, content)| *content)
+}
+
+/// Whether the client asked for a plain-text response, as command line
+/// tools do. Browsers and htmx list `text/html` first and are unaffected.
+fn accepts_plain_text(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|accept| accept.split(',').next())
+        .and_then(|media_range| media_range.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("text/plain"))
+}
+
+/// Cookies and headers handing a newly shared document's delete and edit
+/// tokens to its author.
+fn create_owner_headers(doc: &NewDocument) -> HeaderMap {
+    let cookie_max_age = doc
+        .expires_at
+        .map_or(
+            chrono::Duration::days(MAX_REQUESTED_EXPIRY_DAYS),
+            |expires_at| expires_at - doc.created_at,
+        )
+        .num_seconds();
+    let mut headers = HeaderMap::new();
+    for (cookie_name, token) in [
+        (delete_cookie_name(&doc.id), &doc.delete_token),
+        (edit_cookie_name(&doc.id), &doc.edit_token),
+    ] {
+        headers.append(
+            SET_COOKIE,
+            format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+                cookie_name, token, cookie_max_age
+            )
+            .parse()
+            .unwrap(),
+        );
+    }
+    headers.insert("x-delete-token", doc.delete_token.parse().unwrap());
+    headers.insert("x-edit-token", doc.edit_token.parse().unwrap());
+    headers
+}
+
+/// Sends htmx to a document's view page. The path is checked against the
+/// characters ids and slugs are made of, so nothing else can end up in the
+/// header and redirect somewhere unexpected.
+fn create_htmx_redirect_response(document_id: &str, title: Option<&str>) -> Response {
+    let segment = document_path_segment(document_id, title);
+    let location = is_valid_path_segment(&segment)
+        .then(|| HeaderValue::from_str(&format!("/view/{}", segment)).ok())
+        .flatten();
+    let Some(location) = location else {
+        error!("Refusing to redirect to document {:?}", document_id);
+        return (StatusCode::INTERNAL_SERVER_ERROR, handle_500()).into_response();
+    };
+
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert("hx-redirect", location);
+    (headers, "").into_response()
+}
+
+fn is_valid_path_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Path segment of a document's view URL: the id, prefixed with a slug of
+/// the title when there is one, as in `/view/my-notes-ab12cde`.
+fn document_path_segment(document_id: &str, title: Option<&str>) -> String {
+    match title.and_then(url_slug) {
+        Some(slug) => format!("{}-{}", slug, document_id),
+        None => document_id.to_string(),
+    }
+}
+
+/// Document id from a view URL segment. Ids never contain `-`, so whatever
+/// comes before the last one is a slug and is ignored.
+fn document_id_from_segment(segment: &str) -> &str {
+    segment.rsplit('-').next().unwrap_or(segment)
+}
+
+/// ASCII-only slug of a title for URLs and file names, cut at a word
+/// boundary to `MAX_URL_SLUG_CHARS`.
+fn url_slug(title: &str) -> Option<String> {
+    let slug = slugify(title).replace(|c: char| !c.is_ascii_alphanumeric(), "-");
+    let mut truncated = String::new();
+    for word in slug.split('-').filter(|word| !word.is_empty()) {
+        if !truncated.is_empty() && truncated.len() + 1 + word.len() > MAX_URL_SLUG_CHARS {
+            break;
+        }
+        if !truncated.is_empty() {
+            truncated.push('-');
+        }
+        truncated.push_str(word);
+    }
+    truncated.truncate(MAX_URL_SLUG_CHARS);
+    (!truncated.is_empty()).then_some(truncated)
+}
+
+/// Joins a request path onto the assets directory, refusing anything that
+/// could escape it.
+fn resolve_asset_path(dir: &FilePath, path: &str) -> Option<PathBuf> {
+    let relative = FilePath::new(path.trim_start_matches('/'));
+    let is_contained = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    (is_contained && relative.components().next().is_some()).then(|| dir.join(relative))
+}
+
+fn asset_content_type(path: &FilePath) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        _ => "application/octet-stream",
+    }
+}
+
+fn generate_short_uuid(length: usize) -> String {
+    let mut id = Uuid::new_v4().simple().to_string();
+    id.truncate(length);
+    id
+}
+
+fn generate_token() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+fn delete_cookie_name(document_id: &str) -> String {
+    format!("mdow_delete_{}", document_id)
+}
+
+fn edit_cookie_name(document_id: &str) -> String {
+    format!("mdow_edit_{}", document_id)
+}
+
+fn unlock_cookie_name(document_id: &str) -> String {
+    format!("mdow_unlock_{}", document_id)
+}
+
+fn is_owner(doc: &MarkdownDocument, headers: &HeaderMap) -> bool {
+    doc.delete_token.is_some()
+        && get_cookie(headers, &delete_cookie_name(&doc.id)) == doc.delete_token.as_deref()
+}
+
+fn is_editor(doc: &MarkdownDocument, headers: &HeaderMap) -> bool {
+    doc.edit_token.is_some()
+        && get_cookie(headers, &edit_cookie_name(&doc.id)) == doc.edit_token.as_deref()
+}
+
+/// Whether a request may see a document's content: always for documents
+/// without a password, otherwise for its author, for browsers that unlocked
+/// it, and for clients sending the password in `x-document-password`.
+fn can_read(doc: &MarkdownDocument, headers: &HeaderMap) -> bool {
+    let Some(password_hash) = &doc.password_hash else {
+        return true;
+    };
+
+    is_owner(doc, headers)
+        || is_editor(doc, headers)
+        || (doc.unlock_token.is_some()
+            && get_cookie(headers, &unlock_cookie_name(&doc.id)) == doc.unlock_token.as_deref())
+        || headers
+            .get(DOCUMENT_PASSWORD_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|password| verify_password(password, password_hash))
+}
+
+fn hash_password(password: &str) -> std::result::Result<String, password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn generate_qr_svg(
+    base_url: &str,
+    id: &str,
+    error_correction: EcLevel,
+) -> std::result::Result<String, QrError> {
+    let url = format!("{}/view/{}", base_url, id);
+    let code = QrCode::with_error_correction_level(url, error_correction)?;
+    let svg = code
+        .render::<svg::Color>()
+        .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+        .max_dimensions(QR_MAX_SIZE, QR_MAX_SIZE)
+        .build();
+    Ok(svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single in-memory database. SQLite gives every connection its own
+    /// in-memory database, so the pool is held to one long-lived connection.
+    async fn create_test_storage() -> ShardedStorage {
+        create_test_storage_with_shards(1).await
+    }
+
+    async fn create_test_storage_with_shards(count: usize) -> ShardedStorage {
+        let mut shards = Vec::with_capacity(count);
+        for _ in 0..count {
+            let pool = SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect("sqlite::memory:")
+                .await
+                .unwrap();
+            sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+            shards.push(pool);
+        }
+        ShardedStorage { shards }
+    }
+
+    /// The settings of a deployment that sets no environment variables,
+    /// spelled out so tests don't pick up the developer's environment.
+    fn create_test_config() -> Config {
+        Config {
+            markdown_options: set_markdown_parser_options(MarkdownPreset::Default),
+            max_expiry_days: None,
+            default_expiry_days: DOCUMENT_EXPIRY_DAYS,
+            max_nesting_depth: DEFAULT_MAX_NESTING_DEPTH,
+            max_content_bytes: DEFAULT_MAX_CONTENT_BYTES,
+            id_length: SHORT_ID_LENGTH,
+            external_link_indicators: false,
+            maintenance_mode: false,
+            require_terms: false,
+            terms_url: None,
+            security_headers: vec![
+                (
+                    CONTENT_SECURITY_POLICY,
+                    HeaderValue::from_str(&ASSET_URLS.content_security_policy()).unwrap(),
+                ),
+                (
+                    REFERRER_POLICY,
+                    HeaderValue::from_static(DEFAULT_REFERRER_POLICY),
+                ),
+                (
+                    X_FRAME_OPTIONS,
+                    HeaderValue::from_static(DEFAULT_X_FRAME_OPTIONS),
+                ),
+                (X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+            ],
+            assets_dir: None,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            qr_error_correction: EcLevel::M,
+            metrics_enabled: false,
+            highlight_theme: DEFAULT_HIGHLIGHT_THEME.to_string(),
+            search_enabled: false,
+            allow_indexing: true,
+            stats_token: None,
+            cors_allowed_origins: Vec::new(),
+            cors_allowed_methods: vec![Method::GET, Method::POST],
+            cors_allowed_headers: vec![CONTENT_TYPE],
+            client_ip_header: None,
+        }
+    }
+
+    /// Serves the full router on a free local port and returns its address.
+    async fn spawn_app(config: Config) -> String {
+        spawn_app_with_storage(config, create_test_storage().await).await
+    }
+
+    async fn spawn_app_with_storage(config: Config, storage: ShardedStorage) -> String {
+        spawn_app_with_state(create_test_state(config, storage))
+    }
+
+    fn create_test_state(config: Config, storage: ShardedStorage) -> AppState {
+        AppState {
+            storage: Arc::new(storage),
+            config: Arc::new(config),
+            render_limiter: RenderLimiter::new(4, Duration::from_secs(2)),
+            render_cache: RenderCache::new(0),
+            share_rate_limiter: ShareRateLimiter::new(0, Duration::from_secs(60)),
+        }
+    }
+
+    fn spawn_app_with_state(state: AppState) -> String {
+        let app = setup_router(state);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>()),
+        );
+        format!("http://{}", addr)
+    }
+
+    /// Shares `content` through the editor form and returns the document id
+    /// taken from the `hx-redirect` header.
+    async fn share(app: &str, content: &str) -> String {
+        let response = reqwest::Client::new()
+            .post(format!("{}/share", app))
+            .form(&[("content", content)])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let location = response.headers()["hx-redirect"].to_str().unwrap();
+        let segment = location.strip_prefix("/view/").unwrap();
+        document_id_from_segment(segment).to_string()
+    }
+
+    #[test]
+    fn only_external_links_get_an_indicator() {
+        let mut config = create_test_config();
+        config.base_url = "https://mdow.example".to_string();
+        config.external_link_indicators = true;
+        let html = convert_markdown_to_html(
+            "[out](https://elsewhere.example/) [in](https://mdow.example/view/a) [rel](/view/b)",
+            &config,
+        );
+        assert!(html.contains("out ↗</a>"));
+        assert!(html.contains(">in</a>"));
+        assert!(html.contains(">rel</a>"));
+    }
+
+    #[test]
+    fn external_link_indicator_stays_out_of_heading_slugs() {
+        let mut config = create_test_config();
+        config.external_link_indicators = true;
+        let html = convert_markdown_to_html(
+            "[TOC]\n\n## See [docs](https://elsewhere.example/)",
+            &config,
+        );
+        assert!(html.contains("id=\"see-docs\""));
+        assert!(html.contains(">See docs</a></li>"));
+    }
+
+    #[test]
+    fn abbreviations_are_wrapped_outside_code() {
+        let html = convert_markdown_to_html(
+            "*[HTML]: HyperText Markup Language\n\nWrite HTML, not `HTML`.",
+            &create_test_config(),
+        );
+        assert!(html.contains("<abbr title=\"HyperText Markup Language\">HTML</abbr>, not"));
+        assert!(html.contains("<code>HTML</code>"));
+        assert!(!html.contains("*[HTML]"));
+    }
+
+    #[test]
+    fn abbreviations_leave_image_alt_text_alone() {
+        let html = convert_markdown_to_html(
+            "*[HTML]: HyperText Markup Language\n\n![HTML logo](x.png)",
+            &create_test_config(),
+        );
+        assert!(html.contains("alt=\"HTML logo\""));
+        assert!(!html.contains("abbr"));
+    }
+
+    #[test]
+    fn abbreviations_keep_their_word_in_heading_slugs() {
+        let html = convert_markdown_to_html(
+            "*[HTML]: HyperText Markup Language\n\n[TOC]\n\n## Writing HTML",
+            &create_test_config(),
+        );
+        assert!(html.contains("id=\"writing-html\""));
+        assert!(html.contains(">Writing HTML</a></li>"));
+    }
+
+    #[test]
+    fn client_ip_prefers_the_trusted_header() {
+        let peer = IpAddr::from([10, 0, 0, 1]);
+        let fly_client_ip = HeaderName::from_static("fly-client-ip");
+        let forwarded_for = HeaderName::from_static("x-forwarded-for");
+        let mut headers = HeaderMap::new();
+        headers.insert(&fly_client_ip, "203.0.113.7".parse().unwrap());
+        headers.insert(&forwarded_for, "198.51.100.1, 203.0.113.9".parse().unwrap());
+
+        assert_eq!(
+            client_ip(&headers, Some(&fly_client_ip), peer),
+            IpAddr::from([203, 0, 113, 7])
+        );
+        assert_eq!(
+            client_ip(&headers, Some(&forwarded_for), peer),
+            IpAddr::from([203, 0, 113, 9])
+        );
+        assert_eq!(client_ip(&headers, None, peer), peer);
+        assert_eq!(
+            client_ip(&HeaderMap::new(), Some(&fly_client_ip), peer),
+            peer
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_document_renders_on_its_view_page() {
+        let app = spawn_app(create_test_config()).await;
+        let id = share(&app, "# Round trip\n\nSome *emphasis*.").await;
+
+        let response = reqwest::get(format!("{}/view/{}", app, id)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("<h1 id=\"round-trip\">Round trip"));
+        assert!(body.contains("<em>emphasis</em>"));
+    }
+
+    #[tokio::test]
+    async fn api_share_source_is_listed_for_operators() {
+        let mut config = create_test_config();
+        config.stats_token = Some("secret".to_string());
+        let app = spawn_app(config).await;
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("{}/api/share", app))
+            .header(CONTENT_TYPE.as_str(), "application/json")
+            .body(
+                serde_json::json!({"content": "# From a tool", "source": "notes-plugin/1.2"})
+                    .to_string(),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+
+        let unauthorized = client
+            .get(format!("{}/stats/documents", app))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(unauthorized.status(), reqwest::StatusCode::UNAUTHORIZED);
+        for wrong_token in ["secreT", "secre", "secrets"] {
+            let wrong = client
+                .get(format!("{}/stats/documents", app))
+                .bearer_auth(wrong_token)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(wrong.status(), reqwest::StatusCode::UNAUTHORIZED);
+        }
+
+        let listing = client
+            .get(format!("{}/stats/documents", app))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(listing.status(), reqwest::StatusCode::OK);
+        assert!(listing.text().await.unwrap().contains("notes-plugin/1.2"));
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_rejects_shares_but_serves_views() {
+        let storage = create_test_storage().await;
+        let app = spawn_app_with_storage(create_test_config(), storage.clone()).await;
+        let id = share(&app, "# Before maintenance").await;
+
+        let mut config = create_test_config();
+        config.maintenance_mode = true;
+        let app = spawn_app_with_storage(config, storage.clone()).await;
+        let response = reqwest::Client::new()
+            .post(format!("{}/share", app))
+            .form(&[("content", "# During maintenance")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(RETRY_AFTER.as_str()));
+
+        let response = reqwest::get(format!("{}/view/{}", app, id)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let doc = fetch_markdown_document(storage.shard(&id), &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.views, 0);
+    }
+
+    /// Posts `body` to `/api/share` and returns the status, the content
+    /// type and the JSON body.
+    async fn api_share(app: &str, body: serde_json::Value) -> (u16, String, serde_json::Value) {
+        let response = reqwest::Client::new()
+            .post(format!("{}/api/share", app))
+            .header("content-type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        let content_type = response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = response.text().await.unwrap();
+        (status, content_type, serde_json::from_str(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn api_share_without_content_returns_a_json_error() {
+        let app = spawn_app(create_test_config()).await;
+        let (status, content_type, body) = api_share(&app, serde_json::json!({})).await;
+        assert_eq!(status, 400);
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body["code"], 400);
+        assert!(body["message"].is_string());
+
+        let (status, _, body) = api_share(&app, serde_json::json!({ "content": "  " })).await;
+        assert_eq!(status, 400);
+        assert_eq!(body["code"], 400);
+    }
+
+    #[tokio::test]
+    async fn api_share_during_maintenance_returns_a_json_error() {
+        let mut config = create_test_config();
+        config.maintenance_mode = true;
+        let app = spawn_app(config).await;
+        let (status, content_type, body) =
+            api_share(&app, serde_json::json!({ "content": "# Paused" })).await;
+        assert_eq!(status, 503);
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body["code"], 503);
+    }
+
+    #[tokio::test]
+    async fn api_share_over_the_rate_limit_returns_a_json_error() {
+        let mut state = create_test_state(create_test_config(), create_test_storage().await);
+        state.share_rate_limiter = ShareRateLimiter::new(1, Duration::from_secs(60));
+        let app = spawn_app_with_state(state);
+        let (status, _, _) = api_share(&app, serde_json::json!({ "content": "# One" })).await;
+        assert_eq!(status, 201);
+        let (status, content_type, body) =
+            api_share(&app, serde_json::json!({ "content": "# Two" })).await;
+        assert_eq!(status, 429);
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body["code"], 429);
+    }
+
+    #[tokio::test]
+    async fn imports_count_against_the_share_rate_limit() {
+        let mut state = create_test_state(create_test_config(), create_test_storage().await);
+        state.share_rate_limiter = ShareRateLimiter::new(1, Duration::from_secs(60));
+        let app = spawn_app_with_state(state);
+        let import = || async {
+            reqwest::Client::new()
+                .post(format!("{}/import", app))
+                .form(&[("url", "ftp://example.com/README.md")])
+                .send()
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(import().await, reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(import().await, reqwest::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn shares_wait_for_a_render_permit() {
+        let mut state = create_test_state(create_test_config(), create_test_storage().await);
+        state.render_limiter = RenderLimiter::new(0, Duration::from_millis(10));
+        let app = spawn_app_with_state(state);
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/share", app))
+            .form(&[("content", "# Busy")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(RETRY_AFTER.as_str()));
+
+        let (status, content_type, body) =
+            api_share(&app, serde_json::json!({ "content": "# Busy" })).await;
+        assert_eq!(status, 503);
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body["code"], 503);
+    }
+
+    /// Fetches `path` from the app and returns the response body.
+    async fn get_text(app: &str, path: &str) -> String {
+        reqwest::get(format!("{}{}", app, path))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+
+    /// Shares `content` and returns the document id with its edit token.
+    async fn share_for_editing(app: &str, content: &str) -> (String, String) {
+        let response = reqwest::Client::new()
+            .post(format!("{}/share", app))
+            .form(&[("content", content)])
+            .send()
+            .await
+            .unwrap();
+        let edit_token = response.headers()["x-edit-token"].to_str().unwrap();
+        let location = response.headers()["hx-redirect"].to_str().unwrap();
+        let id = document_id_from_segment(location).to_string();
+        (id, edit_token.to_string())
+    }
+
+    /// Posts an edit of document `id` based on `version`.
+    async fn update(
+        app: &str,
+        id: &str,
+        token: &str,
+        version: i64,
+        content: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/update/{}", app, id))
+            .form(&[
+                ("content", content),
+                ("version", &version.to_string()),
+                ("token", token),
+            ])
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn edit_conflicts_replace_the_editor_version_input() {
+        let app = spawn_app(create_test_config()).await;
+        let (id, token) = share_for_editing(&app, "# Draft").await;
+
+        let editor = reqwest::Client::new()
+            .get(format!("{}/update/{}", app, id))
+            .header("cookie", format!("{}={}", edit_cookie_name(&id), token))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(editor.contains(
+            "<input id=\"edit-version\" class=\"share-option\" type=\"hidden\" name=\"version\" value=\"1\">"
+        ));
+
+        update(&app, &id, &token, 1, "# Other tab").await;
+        let response = update(&app, &id, &token, 1, "# This tab").await;
+        assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+        let body = response.text().await.unwrap();
+        assert_eq!(body.matches("name=\"version\"").count(), 1);
+        assert!(body.contains(
+            "<input id=\"edit-version\" class=\"share-option\" type=\"hidden\" name=\"version\" value=\"2\" hx-swap-oob=\"true\">"
+        ));
+    }
+
+    #[tokio::test]
+    async fn update_redirects_to_the_stored_title_slug() {
+        let app = spawn_app(create_test_config()).await;
+        let client = reqwest::Client::new();
+        let response = client
+            .post(format!("{}/share", app))
+            .form(&[("content", "# First heading"), ("title", "Stored Title")])
+            .send()
+            .await
+            .unwrap();
+        let edit_token = response.headers()["x-edit-token"].to_str().unwrap();
+        let location = response.headers()["hx-redirect"].to_str().unwrap();
+        assert!(location.starts_with("/view/stored-title-"));
+        let id = document_id_from_segment(location).to_string();
+
+        let response = client
+            .post(format!("{}/update/{}", app, id))
+            .form(&[
+                ("content", "# A new heading"),
+                ("version", "1"),
+                ("token", edit_token),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()["hx-redirect"],
+            format!("/view/stored-title-{}", id).as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_document_returns_404() {
+        let app = spawn_app(create_test_config()).await;
+        let response = reqwest::get(format!("{}/view/nosuchid", app))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn count_tasks_counts_done_and_total() {
+        assert_eq!(count_tasks("- [x] one\n- [ ] two\n- [X] three\n"), (2, 3));
+        assert_eq!(count_tasks("- [x] one\n- [x] two\n"), (2, 2));
+        assert_eq!(
+            count_tasks("# Notes\n\n- plain item\n- [not a task]\n"),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn imports_reject_ipv6_ranges_that_reach_internal_addresses() {
+        let public = |ip: &str| is_public_ip(ip.parse().unwrap());
+        assert!(public("2606:4700::1111"));
+        assert!(public("93.184.216.34"));
+        assert!(!public("::1"));
+        assert!(!public("fd00::1"));
+        assert!(!public("fe80::1"));
+        // IPv4-mapped and IPv4-compatible addresses
+        assert!(!public("::ffff:127.0.0.1"));
+        assert!(!public("::10.0.0.1"));
+        assert!(public("::ffff:93.184.216.34"));
+        // NAT64
+        assert!(!public("64:ff9b::7f00:1"));
+        assert!(!public("64:ff9b::a9fe:a9fe"));
+        assert!(public("64:ff9b::5db8:d822"));
+        assert!(!public("64:ff9b:1::c0a8:1"));
+        // 6to4
+        assert!(!public("2002:7f00:1::"));
+        assert!(!public("2002:c0a8:101::1"));
+        assert!(public("2002:5db8:d822::1"));
+        // Site-local and documentation
+        assert!(!public("fec0::1"));
+        assert!(!public("2001:db8::1"));
+    }
+
+    #[test]
+    fn render_cache_is_keyed_by_content() {
+        let cache = RenderCache::new(1024);
+        cache.insert("# Same", Bytes::from("<h1>Same</h1>"));
+        assert_eq!(cache.get("# Same"), Some(Bytes::from("<h1>Same</h1>")));
+        assert_eq!(cache.get("# Other"), None);
+        assert_eq!(RenderCache::new(0).get("# Same"), None);
+    }
+
+    #[test]
+    fn shard_index_uses_the_first_hex_character() {
+        assert_eq!(shard_index("0abc", 4), 0);
+        assert_eq!(shard_index("5abc", 4), 1);
+        assert_eq!(shard_index("fabc", 4), 3);
+        assert_eq!(shard_index("Fabc", 16), 15);
+        assert_eq!(shard_index("zabc", 4), 0);
+        assert_eq!(shard_index("fabc", 1), 0);
+    }
+
+    #[tokio::test]
+    async fn documents_land_in_and_are_read_from_their_shard() {
+        let storage = create_test_storage_with_shards(4).await;
+        let app = spawn_app_with_storage(create_test_config(), storage.clone()).await;
+        for n in 0..8 {
+            let content = format!("# Document {}", n);
+            let id = share(&app, &content).await;
+            let expected = shard_index(&id, 4);
+            for (index, pool) in storage.shards.iter().enumerate() {
+                let doc = fetch_markdown_document(pool, &id).await.unwrap();
+                assert_eq!(
+                    doc.is_some(),
+                    index == expected,
+                    "{} in shard {}",
+                    id,
+                    index
+                );
+            }
+            let doc = storage.fetch_document(&id).await.unwrap().unwrap();
+            assert_eq!(doc.content, content);
+
+            let body = get_text(&app, &format!("/view/{}", id)).await;
+            assert!(body.contains(&format!("Document {}", n)));
+        }
+    }
+
+    #[tokio::test]
+    async fn viewer_embeds_the_raw_content_for_cloning() {
+        let app = spawn_app(create_test_config()).await;
+        let id = share(&app, "# Clone me\n\n<b>& more</b>").await;
+        let body = get_text(&app, &format!("/view/{}", id)).await;
+        assert!(body
+            .contains("<textarea id=\"raw-content\" hidden># Clone me\n\n&lt;b&gt;&amp; more&lt;/b&gt;</textarea>"));
+        assert!(body.contains("id=\"clone-button\""));
+        assert!(body.contains("sessionStorage.setItem('clonedContent', #raw-content.value)"));
+
+        let editor = get_text(&app, "/").await;
+        assert!(editor.contains("sessionStorage.getItem('clonedContent')"));
+    }
+
+    #[test]
+    fn markdown_presets_select_their_extensions() {
+        let commonmark = set_markdown_parser_options("commonmark".parse().unwrap());
+        assert!(!commonmark.contains(Options::ENABLE_TABLES));
+        assert!(!commonmark.contains(Options::ENABLE_STRIKETHROUGH));
+        let full = set_markdown_parser_options("full".parse().unwrap());
+        assert!(full.contains(Options::ENABLE_FOOTNOTES));
+        assert!(full.contains(Options::ENABLE_HEADING_ATTRIBUTES));
+        assert!("fancy".parse::<MarkdownPreset>().is_err());
+
+        let markdown = "| a |\n|---|\n| b |\n\n~~gone~~";
+        let mut config = create_test_config();
+        config.markdown_options = commonmark;
+        let html = convert_markdown_to_html(markdown, &config);
+        assert!(!html.contains("<table>"));
+        assert!(!html.contains("<del>"));
+        config.markdown_options = full;
+        let html = convert_markdown_to_html(markdown, &config);
+        assert!(html.contains("<table>"));
+        assert!(html.contains("<del>gone</del>"));
+    }
+
+    #[tokio::test]
+    async fn fragment_is_the_rendered_content_only() {
+        let storage = create_test_storage().await;
+        let app = spawn_app_with_storage(create_test_config(), storage.clone()).await;
+        let id = share(&app, "# Embedded\n\nSome *text*.").await;
+
+        let response = reqwest::get(format!("{}/fragment/{}", app, id))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            response.headers()["cache-control"],
+            format!("public, max-age={}", VIEW_CACHE_MAX_AGE_SECS)
+        );
+        let etag = response.headers()["etag"].clone();
+        let body = response.text().await.unwrap();
+        assert!(body.contains("<em>text</em>"));
+        assert!(!body.contains("<head"));
+        assert!(!body.contains("<body"));
+
+        let response = reqwest::Client::new()
+            .get(format!("{}/fragment/{}", app, id))
+            .header("if-none-match", etag)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_MODIFIED);
+
+        sqlx::query(
+            "UPDATE markdown_documents SET expires_at = datetime('now', '-1 day') WHERE id = ?",
+        )
+        .bind(&id)
+        .execute(storage.shard(&id))
+        .await
+        .unwrap();
+        let response = reqwest::get(format!("{}/fragment/{}", app, id))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn preview_of_a_pasted_html_page_shows_a_hint() {
+        let app = spawn_app(create_test_config()).await;
+        let preview = |content: &'static str| {
+            let app = app.clone();
+            async move {
+                reqwest::Client::new()
+                    .post(format!("{}/preview", app))
+                    .form(&[("content", content)])
+                    .send()
+                    .await
+                    .unwrap()
+                    .text()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let body = preview("  <!DOCTYPE html>\n<html><body><p>Hi</p></body></html>").await;
+        assert!(body.contains("id=\"html-document-hint\""));
+        assert!(body.contains("mdow renders markdown, not HTML pages"));
+        let body = preview("# Markdown\n\n<b>inline html</b>").await;
+        assert!(!body.contains("html-document-hint"));
+    }
+
+    #[test]
+    fn wrapped_code_blocks_use_a_longer_fence_than_the_content() {
+        assert_eq!(
+            wrap_in_code_block("<html>\n", "html"),
+            "```html\n<html>\n```"
+        );
+        assert_eq!(
+            wrap_in_code_block("<pre>````</pre>", "html"),
+            "`````html\n<pre>````</pre>\n`````"
+        );
+    }
+
+    #[test]
+    fn requested_expiry_is_clamped_to_the_cap() {
+        assert_eq!(clamp_expiry_days(Some(30), Some(7)), Some(7));
+        assert_eq!(clamp_expiry_days(Some(3), Some(7)), Some(3));
+        assert_eq!(clamp_expiry_days(None, Some(7)), Some(7));
+        assert_eq!(clamp_expiry_days(Some(30), None), Some(30));
+        assert_eq!(clamp_expiry_days(None, None), None);
+    }
+
+    #[tokio::test]
+    async fn shares_never_outlive_the_expiry_cap() {
+        let mut config = create_test_config();
+        config.max_expiry_days = Some(7);
+        let app = spawn_app(config).await;
+        let cap = Utc::now() + chrono::Duration::days(7);
+        for request in [
+            serde_json::json!({ "content": "# Long", "expiry_days": 30 }),
+            serde_json::json!({ "content": "# Forever", "never_expires": true }),
+        ] {
+            let (status, _, body) = api_share(&app, request).await;
+            assert_eq!(status, 201);
+            let expires_at: DateTime<Utc> = body["expires_at"].as_str().unwrap().parse().unwrap();
+            assert!((expires_at - cap).num_seconds().abs() < 60);
+        }
+    }
+
+    #[test]
+    fn lint_reports_missing_and_unused_footnotes() {
+        assert_eq!(
+            lint_markdown("Claim[^1].\n"),
+            ["Footnote [^1] is referenced but never defined."]
+        );
+        assert_eq!(
+            lint_markdown("Claim.\n\n[^note]: Unused.\n"),
+            ["Footnote [^note] is defined but never referenced."]
+        );
+        assert!(lint_markdown("Claim[^1].\n\n[^1]: Source.\n").is_empty());
+    }
+
+    #[tokio::test]
+    async fn card_shows_the_document_title() {
+        let app = spawn_app(create_test_config()).await;
+        let id = share(&app, "# Q&A report\n\nNumbers went up.").await;
+
+        let response = reqwest::get(format!("{}/view/{}/card.svg", app, id))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "image/svg+xml");
+        let body = response.text().await.unwrap();
+        assert!(body.starts_with("<svg"));
+        assert!(body.contains("Q&amp;A report"));
+        assert!(body.contains("Numbers went up."));
+
+        let response = reqwest::get(format!("{}/view/unknown/card.svg", app))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn viewer_links_the_card_as_its_preview_image() {
+        let storage = create_test_storage().await;
+        let mut config = create_test_config();
+        config.base_url = "https://mdow.example".to_string();
+        let app = spawn_app_with_storage(config, storage.clone()).await;
+        let id = share(&app, "# Carded\n\nWith a preview.").await;
+        let card_url = format!("https://mdow.example/view/{}/card.svg", id);
+
+        let body = get_text(&app, &format!("/view/{}", id)).await;
+        assert!(body.contains(&format!(
+            "<meta property=\"og:image\" content=\"{}\">",
+            card_url
+        )));
+        assert!(body.contains(&format!(
+            "<meta name=\"twitter:image\" content=\"{}\">",
+            card_url
+        )));
+        assert!(body.contains("<meta name=\"twitter:card\" content=\"summary_large_image\">"));
+
+        let mut doc = fetch_markdown_document(storage.shard(&id), &id)
+            .await
+            .unwrap()
+            .unwrap();
+        doc.password_hash = Some(hash_password("secret").unwrap());
+        let page =
+            create_markdown_viewer_page(&doc, Bytes::new(), &create_test_config(), false, false)
+                .concat();
+        let page = String::from_utf8(page).unwrap();
+        assert!(!page.contains("og:image"));
+        assert!(!page.contains("twitter:image"));
+    }
+
+    #[tokio::test]
+    async fn front_matter_keywords_fill_the_viewer_keywords_meta() {
+        let app = spawn_app(create_test_config()).await;
+
+        let id = share(
+            &app,
+            "---\ntitle: Tagged\nkeywords: [rust, \"<script>web\"]\n---\n\n# Tagged",
+        )
+        .await;
+        let body = get_text(&app, &format!("/view/{}", id)).await;
+        assert!(body.contains("<meta name=\"keywords\" content=\"rust, scriptweb\">"));
+
+        let id = share(&app, "# Untagged").await;
+        let body = get_text(&app, &format!("/view/{}", id)).await;
+        assert!(body.contains(&format!(
+            "<meta name=\"keywords\" content=\"{}\">",
+            DEFAULT_KEYWORDS
+        )));
+    }
+
+    #[tokio::test]
+    async fn deeply_nested_documents_are_rejected_before_rendering() {
+        let deep = format!("{} too deep", ">".repeat(5000));
+        assert!(exceeds_nesting_depth(&deep, DEFAULT_MAX_NESTING_DEPTH));
+        assert!(!exceeds_nesting_depth(
+            "> > > fine\n\n- a\n  - b",
+            DEFAULT_MAX_NESTING_DEPTH
+        ));
+
+        let app = spawn_app(create_test_config()).await;
+        let response = reqwest::Client::new()
+            .post(format!("{}/share", app))
+            .form(&[("content", deep.as_str())])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn render_limiter_bounds_concurrent_renders() {
+        let limiter = RenderLimiter::new(2, Duration::from_millis(20));
+        let first = limiter.acquire().await;
+        let second = limiter.acquire().await;
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.acquire().await.is_none());
+
+        drop(first);
+        assert!(limiter.acquire().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn editor_is_prefilled_from_a_known_template() {
+        assert!(find_template("meeting-notes").is_some());
+        assert!(find_template("novel").is_none());
+
+        let app = spawn_app(create_test_config()).await;
+        assert!(get_text(&app, "/?template=meeting-notes")
+            .await
+            .contains("# Meeting notes\n\n**Date:** "));
+        assert!(!get_text(&app, "/?template=novel")
+            .await
+            .contains("# Meeting notes"));
+    }
+
+    #[tokio::test]
+    async fn shares_require_accepted_terms_when_configured() {
+        let mut config = create_test_config();
+        config.require_terms = true;
+        config.terms_url = Some("https://mdow.example/terms".to_string());
+        let app = spawn_app(config).await;
+        let client = reqwest::Client::new();
+
+        let editor = get_text(&app, "/").await;
+        assert!(editor.contains("name=\"terms_accepted\""));
+        assert!(editor.contains("href=\"https://mdow.example/terms\""));
+
+        let response = client
+            .post(format!("{}/share", app))
+            .form(&[("content", "# Terms")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("accept the terms of use"));
+
+        let response = client
+            .post(format!("{}/share", app))
+            .form(&[("content", "# Terms"), ("terms_accepted", "true")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.headers().contains_key("hx-redirect"));
+
+        let app = spawn_app(create_test_config()).await;
+        let editor = get_text(&app, "/").await;
+        assert!(!editor.contains("terms_accepted"));
+    }
+
+    #[test]
+    fn footnotes_are_numbered_by_first_reference_with_back_references() {
+        let html = convert_markdown_to_html(
+            "Second[^b] and first[^a] and again[^b].\n\n[^a]: Alpha.\n\n[^b]: Beta.\n",
+            &create_test_config(),
+        );
+        assert!(html.contains("<sup class=\"footnote-reference\" id=\"fnref-1\"><a href=\"#fn-1\""));
+        assert!(html.contains("<sup class=\"footnote-reference\" id=\"fnref-2\"><a href=\"#fn-2\""));
+        assert!(html.contains("<sup class=\"footnote-reference\"><a href=\"#fn-1\""));
+
+        let list = &html[html.find("<section class=\"footnotes\">\n<ol>").unwrap()..];
+        let beta = list.find("<li id=\"fn-1\" value=\"1\">\n<p>Beta.").unwrap();
+        let alpha = list
+            .find("<li id=\"fn-2\" value=\"2\">\n<p>Alpha.")
+            .unwrap();
+        assert!(beta < alpha);
+        assert!(list.contains("<a class=\"footnote-backref\" href=\"#fnref-1\""));
+        assert!(list.contains("<a class=\"footnote-backref\" href=\"#fnref-2\""));
+    }
+
+    #[test]
+    fn smart_punctuation_leaves_code_alone() {
+        let html = convert_markdown_to_html(
+            "\"hello\" -- there...\n\n`\"code\" --`\n\n```\n\"fence\" -- ...\n```\n",
+            &create_test_config(),
+        );
+        assert!(html.contains("<p>“hello” – there…</p>"));
+        assert!(html.contains("<code>\"code\" --</code>"));
+        assert!(html.contains("\"fence\" -- ..."));
+    }
+
+    #[test]
+    fn words_are_counted_outside_code_and_per_cjk_character() {
+        assert_eq!(count_words("# Title\n\nTwo words, and *three* more."), 6);
+        assert_eq!(
+            count_words("Before\n\n```\nnot counted at all\n```\n\nafter"),
+            2
+        );
+        assert_eq!(count_words("日本語 text"), 4);
+        assert_eq!(count_words(""), 0);
+    }
+
+    #[test]
+    fn reading_time_rounds_up_to_at_least_a_minute() {
+        assert_eq!(estimate_reading_minutes(0), 1);
+        assert_eq!(estimate_reading_minutes(READING_WORDS_PER_MINUTE), 1);
+        assert_eq!(estimate_reading_minutes(READING_WORDS_PER_MINUTE + 1), 2);
+    }
+
+    #[test]
+    fn markdown_options_are_parsed_from_a_list() {
+        assert_eq!(
+            parse_markdown_options("tables, Strikethrough,,smart"),
+            Options::ENABLE_TABLES
+                | Options::ENABLE_STRIKETHROUGH
+                | Options::ENABLE_SMART_PUNCTUATION
+        );
+        assert_eq!(
+            parse_markdown_options("footnotes,sparkles"),
+            Options::ENABLE_FOOTNOTES
+        );
+        assert_eq!(parse_markdown_options(""), Options::empty());
+    }
+
+    #[tokio::test]
+    async fn missing_and_deleted_documents_return_404_pages() {
+        let storage = create_test_storage().await;
+        let app = spawn_app_with_storage(create_test_config(), storage.clone()).await;
+        let response = reqwest::get(format!("{}/view/abcdef-some-title", app))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("404 - Page Not Found"));
+
+        let id = share(&app, "# Short lived").await;
+        sqlx::query("DELETE FROM markdown_documents WHERE id = ?")
+            .bind(&id)
+            .execute(storage.shard(&id))
+            .await
+            .unwrap();
+        let response = reqwest::get(format!("{}/view/{}", app, id)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn sanitizer_keeps_markup_the_viewer_needs_and_drops_scripts() {
+        let html = convert_markdown_to_html(
+            "<script>alert(1)</script>\n\n<h2 id=\"x\">Kept</h2>\n\n<img src=\"a.png\" onerror=\"alert(1)\">\n\n<span class=\"mjx\" onclick=\"alert(1)\">$x$</span>",
+            &create_test_config(),
+        );
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("alert(1)"));
+        assert!(html.contains("<h2 id=\"x\">Kept</h2>"));
+        assert!(html.contains("<img src=\"a.png\">"));
+        assert!(html.contains("<span class=\"mjx\">$x$</span>"));
+    }
+
+    #[test]
+    fn emoji_shortcodes_expand_outside_code() {
+        let html = convert_markdown_to_html(
+            ":+1: :rocket: :notarealcode: `:tada:`\n\n```\n:tada:\n```\n",
+            &create_test_config(),
+        );
+        assert!(html.contains("<p>👍 🚀 :notarealcode: <code>:tada:</code></p>"));
+        assert!(html.contains("<code>:tada:\n</code>"));
+    }
+
+    #[tokio::test]
+    async fn qr_codes_and_share_urls_use_the_configured_base() {
+        let expected =
+            QrCode::with_error_correction_level("https://md.example.org/view/abc123", EcLevel::M)
+                .unwrap()
+                .render::<svg::Color>()
+                .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+                .max_dimensions(QR_MAX_SIZE, QR_MAX_SIZE)
+                .build();
+        let qr_svg = generate_qr_svg("https://md.example.org", "abc123", EcLevel::M).unwrap();
+        assert_eq!(qr_svg, expected);
+        assert_ne!(
+            generate_qr_svg("https://mdow.yree.io", "abc123", EcLevel::M).unwrap(),
+            expected
+        );
+
+        let mut config = create_test_config();
+        config.base_url = "https://md.example.org".to_string();
+        let app = spawn_app(config).await;
+        let response = reqwest::Client::new()
+            .post(format!("{}/share", app))
+            .header("accept", "text/plain")
+            .form(&[("content", "plain")])
+            .send()
+            .await
+            .unwrap();
+        let url = response.text().await.unwrap();
+        assert!(url.starts_with("https://md.example.org/view/"));
+    }
+
+    #[test]
+    fn each_alert_type_renders_as_a_callout() {
+        let config = create_test_config();
+        for (marker, class, title) in [
+            ("NOTE", "callout-note", "Note"),
+            ("TIP", "callout-tip", "Tip"),
+            ("IMPORTANT", "callout-important", "Important"),
+            ("WARNING", "callout-warning", "Warning"),
+            ("CAUTION", "callout-caution", "Caution"),
+        ] {
+            let html = convert_markdown_to_html(&format!("> [!{}]\n> Body", marker), &config);
+            assert_eq!(
+                html.trim(),
+                format!(
+                    "<div class=\"callout {}\"><p class=\"callout-title\">{}</p>\n<p>Body</p>\n</div>",
+                    class, title
+                )
+            );
+        }
+
+        let html = convert_markdown_to_html("> [!BOGUS]\n> Plain", &config);
+        assert!(html.starts_with("<blockquote>"));
+        assert!(html.contains("[!BOGUS]"));
+    }
+
+    #[tokio::test]
+    async fn blank_shares_are_rejected_without_storing_anything() {
+        let storage = create_test_storage().await;
+        let app = spawn_app_with_storage(create_test_config(), storage.clone()).await;
+        for content in [" \n\t ", "<script></script>"] {
+            let response = reqwest::Client::new()
+                .post(format!("{}/share", app))
+                .form(&[("content", content)])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        }
+
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM markdown_documents")
+            .fetch_one(storage.shard("0"))
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn plain_text_drops_markup_code_blocks_and_html() {
+        assert_eq!(
+            extract_plain_text(
+                "# Heading *one*\n\n- item [link](https://x.y) text\n- second\n\n```rust\nlet x = 1;\n```\n\n<div>html</div>\n\nEnd   of\ttext."
+            ),
+            "Heading one item link text second End of text."
+        );
+        assert_eq!(extract_plain_text(""), "");
+    }
+
+    #[test]
+    fn task_lists_get_github_classes_and_keep_their_state() {
+        let html = convert_markdown_to_html("- [x] done\n- [ ] todo\n", &create_test_config());
+        assert!(html.contains("<ul class=\"contains-task-list\">"));
+        assert!(html.contains(
+            "<li class=\"task-list-item\"><input disabled=\"\" type=\"checkbox\" checked=\"\">\ndone</li>"
+        ));
+        assert!(html.contains(
+            "<li class=\"task-list-item\"><input disabled=\"\" type=\"checkbox\">\ntodo</li>"
+        ));
+
+        let html = convert_markdown_to_html("- plain\n", &create_test_config());
+        assert!(html.contains("<ul>\n<li>plain</li>"));
+    }
+
+    #[test]
+    fn bind_address_falls_back_to_all_interfaces() {
+        assert_eq!(
+            parse_bind_ip(Some("127.0.0.1")),
+            IpAddr::from([127, 0, 0, 1])
+        );
+        assert_eq!(parse_bind_ip(Some("::1")), "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(parse_bind_ip(Some("localhost")), IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(parse_bind_ip(Some("")), IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(parse_bind_ip(None), IpAddr::from([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn only_absolute_links_open_in_a_new_tab() {
+        let html = convert_markdown_to_html(
+            "[rel](docs/a.md) ![img](img.png) [abs](https://example.com/) [anchor](#top)",
+            &create_test_config(),
+        );
+        assert!(html.contains("<a href=\"docs/a.md\" rel=\"noopener noreferrer\">rel</a>"));
+        assert!(html.contains("<img src=\"img.png\" alt=\"img\">"));
+        assert!(html.contains(
+            "<a href=\"https://example.com/\" target=\"_blank\" rel=\"noopener noreferrer\">abs</a>"
+        ));
+        assert!(html.contains("<a href=\"#top\" rel=\"noopener noreferrer\">anchor</a>"));
+    }
+
+    #[test]
+    fn redirects_refuse_crafted_document_ids() {
+        let response = create_htmx_redirect_response("abc123", Some("My title"));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["hx-redirect"], "/view/my-title-abc123");
+
+        for id in [
+            "//evil.example",
+            "abc\r\nSet-Cookie: x=1",
+            "../admin",
+            "abc?next=https://evil.example",
+            "",
+        ] {
+            let response = create_htmx_redirect_response(id, None);
+            assert_eq!(
+                response.status(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "{:?}",
+                id
+            );
+            assert!(!response.headers().contains_key("hx-redirect"));
+        }
+    }
+
+    #[test]
+    fn id_length_is_clamped() {
+        assert_eq!(parse_id_length(None), SHORT_ID_LENGTH);
+        assert_eq!(parse_id_length(Some("12")), 12);
+        assert_eq!(parse_id_length(Some("1")), MIN_ID_LENGTH);
+        assert_eq!(parse_id_length(Some("100")), MAX_ID_LENGTH);
+        assert_eq!(parse_id_length(Some("long")), SHORT_ID_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn generated_ids_have_the_configured_length() {
+        for length in [MIN_ID_LENGTH, SHORT_ID_LENGTH, MAX_ID_LENGTH] {
+            assert_eq!(generate_short_uuid(length).len(), length);
+        }
+
+        let mut config = create_test_config();
+        config.id_length = 12;
+        let app = spawn_app(config).await;
+        let id = share(&app, "# Longer id").await;
+        assert_eq!(id.len(), 12);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn expiry_is_described_in_the_largest_whole_unit() {
+        let now = Utc::now();
+        let expires_in = |duration: chrono::Duration| format_expires_in(Some(now + duration), now);
+        assert_eq!(expires_in(chrono::Duration::days(12)), "expires in 12 days");
+        assert_eq!(
+            expires_in(chrono::Duration::days(1) + chrono::Duration::hours(5)),
+            "expires in 1 day"
+        );
+        assert_eq!(expires_in(chrono::Duration::hours(5)), "expires in 5 hours");
+        assert_eq!(
+            expires_in(chrono::Duration::minutes(1)),
+            "expires in 1 minute"
+        );
+        assert_eq!(
+            expires_in(chrono::Duration::seconds(30)),
+            "expires in less than a minute"
+        );
+        assert_eq!(format_expires_in(None, now), "never expires");
+    }
+
+    #[test]
+    fn definition_lists_render_single_and_multiple_definitions() {
+        let config = create_test_config();
+        let html = convert_markdown_to_html("Term\n: Only definition\n", &config);
+        assert!(html.contains("<dl>\n<dt>Term</dt>\n<dd>Only definition</dd>\n</dl>"));
+
+        let html = convert_markdown_to_html("Term\n: First\n: Second\n\nOther\n: Only\n", &config);
+        assert!(html.contains(
+            "<dl>\n<dt>Term</dt>\n<dd>First</dd>\n<dd>Second</dd>\n<dt>Other</dt>\n<dd>Only</dd>\n</dl>"
+        ));
+
+        let html = convert_markdown_to_html("Not a term\n\n: not a definition\n", &config);
+        assert!(!html.contains("<dl>"));
+    }
+
+    #[test]
+    fn optional_html_tags_are_kept_only_when_allowed() {
+        let html = "<kbd>Ctrl</kbd> <mark>hi</mark> <details><summary>More</summary>Body</details>";
+
+        let conservative = create_sanitizer(parse_allowed_html_tags(""));
+        let cleaned = conservative.clean(html).to_string();
+        assert!(!cleaned.contains("<kbd>"));
+        assert!(!cleaned.contains("<mark>"));
+        assert!(cleaned.contains("Ctrl"));
+
+        let allowed = parse_allowed_html_tags("kbd, MARK, script, details, summary");
+        assert_eq!(allowed, ["kbd", "mark", "details", "summary"]);
+        let cleaned = create_sanitizer(allowed).clean(html).to_string();
+        assert!(cleaned.contains("<kbd>Ctrl</kbd>"));
+        assert!(cleaned.contains("<mark>hi</mark>"));
+        assert!(cleaned.contains("<details><summary>More</summary>Body</details>"));
+        assert_eq!(
+            create_sanitizer(parse_allowed_html_tags("script"))
+                .clean("<script>alert(1)</script>")
+                .to_string(),
+            ""
+        );
+    }
+
+    /// Sends a CORS preflight for a JSON POST from `origin` to `path`.
+    async fn preflight(app: &str, path: &str, origin: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .request(reqwest::Method::OPTIONS, format!("{}{}", app, path))
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "content-type")
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn api_preflight_returns_the_configured_cors_headers() {
+        let mut config = create_test_config();
+        config.cors_allowed_origins = vec![HeaderValue::from_static("https://tools.example")];
+        let app = spawn_app(config).await;
+
+        let response = preflight(&app, "/api/share", "https://tools.example").await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://tools.example"
+        );
+        assert_eq!(headers["access-control-allow-methods"], "GET,POST");
+        assert_eq!(headers["access-control-allow-headers"], "content-type");
+
+        let response = preflight(&app, "/api/share", "https://other.example").await;
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+        let response = preflight(&app, "/share", "https://tools.example").await;
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn api_sends_no_cors_headers_without_an_allow_list() {
+        let app = spawn_app(create_test_config()).await;
+        let response = preflight(&app, "/api/share", "https://tools.example").await;
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+    }
+
+    #[tokio::test]
+    async fn view_urls_resolve_whatever_the_slug() {
+        assert_eq!(document_id_from_segment("my-notes-ab12cde"), "ab12cde");
+        assert_eq!(document_id_from_segment("ab12cde"), "ab12cde");
+        assert_eq!(
+            document_path_segment("ab12cde", Some("My Notes: Day 1!")),
+            "my-notes-day-1-ab12cde"
+        );
+        assert_eq!(document_path_segment("ab12cde", Some("日本語")), "ab12cde");
+        assert_eq!(document_path_segment("ab12cde", None), "ab12cde");
+        let long_title = "word ".repeat(40);
+        let segment = document_path_segment("ab12cde", Some(&long_title));
+        assert!(segment.len() <= MAX_URL_SLUG_CHARS + "-ab12cde".len());
+        assert!(segment.ends_with("word-ab12cde"));
+
+        let app = spawn_app(create_test_config()).await;
+        let id = share(&app, "# Slugged").await;
+        let response = reqwest::get(format!("{}/view/renamed-by-hand-{}", app, id))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.text().await.unwrap().contains("Slugged"));
+    }
+
+    #[tokio::test]
+    async fn oversized_shares_are_reported_in_the_editor_error_element() {
+        let mut config = create_test_config();
+        config.max_content_bytes = 16;
+        let app = spawn_app(config).await;
+
+        let editor = get_text(&app, "/").await;
+        assert!(editor.contains("<div class=\"grid\" data-error-target=\"#editor-error\">"));
+        assert!(editor.contains("<div id=\"editor-error\" role=\"alert\"></div>"));
+        assert!(editor.contains("on htmx:beforeSwap"));
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/share", app))
+            .form(&[("content", "# More than sixteen bytes")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("This document is too large to share."));
+    }
+
+    #[tokio::test]
+    async fn versioned_update_bumps_the_stored_version() {
+        let storage = create_test_storage().await;
+        let app = spawn_app_with_storage(create_test_config(), storage.clone()).await;
+        let (id, token) = share_for_editing(&app, "# Version one").await;
+
+        let response = update(&app, &id, &token, 1, "# Version two").await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let doc = fetch_markdown_document(storage.shard(&id), &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.version, 2);
+        assert_eq!(doc.content, "# Version two");
+    }
+
+    #[tokio::test]
+    async fn search_follows_edited_titles_across_shards() {
+        let mut config = create_test_config();
+        config.search_enabled = true;
+        let app = spawn_app_with_storage(config, create_test_storage_with_shards(2).await).await;
+        let (id, token) = share_for_editing(&app, "# Old heading\n\nmeadow").await;
+        share(&app, "# Other\n\nmeadow meadow meadow").await;
+
+        let response = update(&app, &id, &token, 1, "# New heading\n\nmeadow").await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let body = get_text(&app, "/search?q=meadow").await;
+        let results: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        let edited = results.iter().find(|result| result["id"] == id).unwrap();
+        assert_eq!(edited["title"], "New heading");
+        assert!(edited.get("rank").is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_update_conflicts_with_the_current_content() {
+        let storage = create_test_storage().await;
+        let app = spawn_app_with_storage(create_test_config(), storage.clone()).await;
+        let (id, token) = share_for_editing(&app, "# Version one").await;
+        update(&app, &id, &token, 1, "# Saved <elsewhere>").await;
+
+        let response = update(&app, &id, &token, 1, "# Stale edit").await;
+        assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("# Saved &lt;elsewhere&gt;"));
+        let doc = fetch_markdown_document(storage.shard(&id), &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.version, 2);
+        assert_eq!(doc.content, "# Saved <elsewhere>");
+    }
+
+    #[tokio::test]
+    async fn every_share_path_requires_accepted_terms() {
+        let mut config = create_test_config();
+        config.require_terms = true;
+        let app = spawn_app(config).await;
+        let client = reqwest::Client::new();
+
+        let (status, _, body) = api_share(&app, serde_json::json!({ "content": "# API" })).await;
+        assert_eq!(status, 400);
+        assert_eq!(body["code"], 400);
+        let (status, _, _) = api_share(
+            &app,
+            serde_json::json!({ "content": "# API", "terms_accepted": true }),
+        )
+        .await;
+        assert_eq!(status, 201);
+
+        let bundle = |terms_accepted: Option<&'static str>| {
+            let mut fields = vec![("content", "# One"), ("content", "# Two")];
+            fields.extend(terms_accepted.map(|value| ("terms_accepted", value)));
+            client
+                .post(format!("{}/share-bundle", app))
+                .form(&fields)
+                .send()
+        };
+        let response = bundle(None).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("accept the terms of use"));
+        let response = bundle(Some("true")).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.headers().contains_key("hx-redirect"));
+
+        let response = client
+            .post(format!("{}/share", app))
+            .form(&[("content", "# Source"), ("terms_accepted", "true")])
+            .send()
+            .await
+            .unwrap();
+        let location = response.headers()["hx-redirect"].to_str().unwrap();
+        let id = document_id_from_segment(location).to_string();
+        let fork = |form: &'static [(&'static str, &'static str)]| {
+            client
+                .post(format!("{}/fork/{}", app, id))
+                .form(form)
+                .send()
+        };
+        let response = fork(&[]).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        let response = fork(&[("terms_accepted", "true")]).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.headers().contains_key("hx-redirect"));
+    }
+
+    #[tokio::test]
+    async fn blank_updates_leave_the_document_untouched() {
+        let storage = create_test_storage().await;
+        let app = spawn_app_with_storage(create_test_config(), storage.clone()).await;
+        let (id, token) = share_for_editing(&app, "# Keep me").await;
+
+        let response = update(&app, &id, &token, 1, " \n\t ").await;
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        let doc = fetch_markdown_document(storage.shard(&id), &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.version, 1);
+        assert_eq!(doc.content, "# Keep me");
+    }
+
+    #[tokio::test]
+    async fn busy_previews_explain_why_they_failed() {
+        let mut state = create_test_state(create_test_config(), create_test_storage().await);
+        state.render_limiter = RenderLimiter::new(0, Duration::from_millis(10));
+        let app = spawn_app_with_state(state);
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/preview", app))
+            .form(&[("content", "# Busy")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(RETRY_AFTER.as_str()));
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("<blockquote id=\"render-busy\">"));
+    }
+
+    #[tokio::test]
+    async fn view_etag_follows_the_expires_in_countdown() {
+        let storage = create_test_storage().await;
+        let app = spawn_app_with_storage(create_test_config(), storage.clone()).await;
+        let id = share(&app, "# Counting down").await;
+        let doc = fetch_markdown_document(storage.shard(&id), &id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let now = Utc::now();
+        let etag = create_view_etag(&doc, false, false, now);
+        assert_eq!(
+            etag,
+            create_view_etag(&doc, false, false, now + chrono::Duration::minutes(1))
+        );
+        assert_ne!(
+            etag,
+            create_view_etag(&doc, false, false, now + chrono::Duration::days(2))
+        );
+
+        let response = reqwest::get(format!("{}/view/{}", app, id)).await.unwrap();
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let response = reqwest::Client::new()
+            .get(format!("{}/view/{}", app, id))
+            .header("if-none-match", &etag)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_MODIFIED);
+
+        sqlx::query(
+            "UPDATE markdown_documents SET expires_at = datetime('now', '+3 hours') WHERE id = ?",
+        )
+        .bind(&id)
+        .execute(storage.shard(&id))
+        .await
+        .unwrap();
+        let response = reqwest::Client::new()
+            .get(format!("{}/view/{}", app, id))
+            .header("if-none-match", &etag)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains("expires in 2 hours"));
+    }
 }